@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS contests (
+    guild_id VARCHAR(255) NOT NULL,
+    git_remote_url TEXT NOT NULL,
+  	contest_rel_path TEXT NOT NULL,
+  	private_key BYTEA,
+    PRIMARY KEY (guild_id)
+);
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS renderer_url TEXT;
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS default_branch TEXT;
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS default_lang TEXT;
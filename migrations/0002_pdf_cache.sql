@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS pdf_cache (
+    cache_key CHAR(64) NOT NULL,
+    guild_id VARCHAR(255) NOT NULL,
+    task_name TEXT NOT NULL,
+    commit_sha CHAR(40) NOT NULL,
+    renderer TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (cache_key)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS known_hosts (
+    guild_id VARCHAR(255) NOT NULL,
+    host TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    key_type TEXT,
+    public_key BYTEA,
+    fingerprint TEXT NOT NULL,
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (guild_id, host, port)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS private_key_version INTEGER;
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS https_username TEXT;
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS https_token BYTEA;
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS https_token_version INTEGER;
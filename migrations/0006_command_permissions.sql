@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS command_permissions (
+    guild_id VARCHAR(255) NOT NULL,
+    capability TEXT NOT NULL,
+    role_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (guild_id, capability, role_id)
+);
@@ -0,0 +1,18 @@
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS name TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE contests ADD COLUMN IF NOT EXISTS channel_id VARCHAR(255);
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM information_schema.key_column_usage
+        WHERE table_name = 'contests' AND constraint_name = 'contests_pkey' AND column_name = 'name'
+    ) THEN
+        ALTER TABLE contests DROP CONSTRAINT contests_pkey;
+        ALTER TABLE contests ADD PRIMARY KEY (guild_id, name);
+    END IF;
+END $$;
+CREATE TABLE IF NOT EXISTS active_contests (
+    guild_id VARCHAR(255) NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (guild_id)
+);
+ALTER TABLE contest_config_history ADD COLUMN IF NOT EXISTS contest_name TEXT NOT NULL DEFAULT 'default';
@@ -0,0 +1,10 @@
+CREATE TABLE contests (
+    guild_id VARCHAR(255) NOT NULL,
+    git_remote_url TEXT NOT NULL,
+    contest_rel_path TEXT NOT NULL,
+    private_key BLOB,
+    renderer_url TEXT,
+    default_branch TEXT,
+    default_lang TEXT,
+    PRIMARY KEY (guild_id)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE pdf_cache (
+    cache_key CHAR(64) NOT NULL,
+    guild_id VARCHAR(255) NOT NULL,
+    task_name TEXT NOT NULL,
+    commit_sha CHAR(40) NOT NULL,
+    renderer TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (cache_key)
+);
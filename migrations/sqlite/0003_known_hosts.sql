@@ -0,0 +1,11 @@
+CREATE TABLE known_hosts (
+    guild_id VARCHAR(255) NOT NULL,
+    host TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    key_type TEXT,
+    public_key BLOB,
+    fingerprint TEXT NOT NULL,
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    added_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (guild_id, host, port)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE contests ADD COLUMN private_key_version INTEGER;
+ALTER TABLE contests ADD COLUMN https_username TEXT;
+ALTER TABLE contests ADD COLUMN https_token BLOB;
+ALTER TABLE contests ADD COLUMN https_token_version INTEGER;
@@ -0,0 +1,19 @@
+CREATE TABLE contest_config_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id VARCHAR(255) NOT NULL,
+    changed_by VARCHAR(255) NOT NULL,
+    changed_by_name TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    action TEXT NOT NULL,
+    git_remote_url TEXT,
+    contest_rel_path TEXT,
+    private_key BLOB,
+    private_key_version INTEGER,
+    https_username TEXT,
+    https_token BLOB,
+    https_token_version INTEGER,
+    renderer_url TEXT,
+    default_branch TEXT,
+    default_lang TEXT
+);
+CREATE INDEX contest_config_history_guild_id ON contest_config_history (guild_id, id);
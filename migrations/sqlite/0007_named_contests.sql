@@ -0,0 +1,27 @@
+-- SQLite can't change a primary key, so the table is rebuilt.
+CREATE TABLE contests_by_name (
+    guild_id VARCHAR(255) NOT NULL,
+    name TEXT NOT NULL DEFAULT 'default',
+    channel_id VARCHAR(255),
+    git_remote_url TEXT NOT NULL,
+    contest_rel_path TEXT NOT NULL,
+    private_key BLOB,
+    private_key_version INTEGER,
+    https_username TEXT,
+    https_token BLOB,
+    https_token_version INTEGER,
+    renderer_url TEXT,
+    default_branch TEXT,
+    default_lang TEXT,
+    PRIMARY KEY (guild_id, name)
+);
+INSERT INTO contests_by_name (guild_id, git_remote_url, contest_rel_path, private_key, private_key_version, https_username, https_token, https_token_version, renderer_url, default_branch, default_lang)
+    SELECT guild_id, git_remote_url, contest_rel_path, private_key, private_key_version, https_username, https_token, https_token_version, renderer_url, default_branch, default_lang FROM contests;
+DROP TABLE contests;
+ALTER TABLE contests_by_name RENAME TO contests;
+CREATE TABLE active_contests (
+    guild_id VARCHAR(255) NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (guild_id)
+);
+ALTER TABLE contest_config_history ADD COLUMN contest_name TEXT NOT NULL DEFAULT 'default';
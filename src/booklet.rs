@@ -0,0 +1,506 @@
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream};
+
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+/// A4 in points, used for the pages the booklet adds itself.
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const TOC_LINES_PER_PAGE: usize = 30;
+/// Page attributes a page may inherit from its ancestors in the page tree.
+const INHERITABLE_KEYS: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// What goes on the cover page.
+pub struct Cover {
+    pub title: String,
+    pub date: Option<String>,
+    /// A JPEG image drawn above the title.
+    pub logo: Option<Vec<u8>>,
+}
+
+/// Merges the task PDFs, in the given order, into one document that starts
+/// with a cover page and a table of contents. Every section is padded with
+/// blank pages to an even length so each one starts on a right-hand page when
+/// printed duplex.
+pub fn build_booklet(
+    cover: &Cover,
+    tasks: &[(String, Vec<u8>)],
+) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+    let mut sources = Vec::new();
+    for (name, pdf) in tasks {
+        match Document::load_mem(pdf) {
+            Ok(doc) => sources.push(doc),
+            Err(e) => Err(MyError::new(&format!("[pdf] cannot read {}: {}", name, e)))?,
+        }
+    }
+
+    let toc_pages = padded(tasks.len().div_ceil(TOC_LINES_PER_PAGE).max(1));
+    let mut next_page = padded(1) + toc_pages + 1;
+    let mut toc_entries = Vec::new();
+    for ((name, _), source) in tasks.iter().zip(sources.iter()) {
+        toc_entries.push((name.clone(), next_page));
+        next_page += padded(source.get_pages().len());
+    }
+
+    let mut booklet = Document::with_version("1.5");
+    let pages_id = booklet.new_object_id();
+    let font_id = booklet.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let mut kids = Vec::new();
+
+    let cover_page = cover_page(&mut booklet, pages_id, font_id, cover)?;
+    push_padded(&mut booklet, pages_id, &mut kids, vec![cover_page])?;
+
+    let mut toc = Vec::new();
+    for (i, chunk) in toc_entries.chunks(TOC_LINES_PER_PAGE).enumerate() {
+        toc.push(toc_page(&mut booklet, pages_id, font_id, i == 0, chunk)?);
+    }
+    if toc.is_empty() {
+        toc.push(toc_page(&mut booklet, pages_id, font_id, true, &[])?);
+    }
+    push_padded(&mut booklet, pages_id, &mut kids, toc)?;
+
+    for source in sources {
+        let imported = import_pages(&mut booklet, pages_id, source)?;
+        push_padded(&mut booklet, pages_id, &mut kids, imported)?;
+    }
+
+    let count = kids.len() as i64;
+    booklet.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+            "Count" => count,
+        }),
+    );
+    let catalog_id = booklet.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    booklet.trailer.set("Root", catalog_id);
+    booklet.compress();
+
+    let mut out = Vec::new();
+    booklet.save_to(&mut out)?;
+    Ok(out)
+}
+
+fn padded(pages: usize) -> usize {
+    pages + pages % 2
+}
+
+fn push_padded(
+    booklet: &mut Document,
+    pages_id: ObjectId,
+    kids: &mut Vec<ObjectId>,
+    pages: Vec<ObjectId>,
+) -> Result<(), TaskPdfWriterBotError> {
+    let blanks = padded(pages.len()) - pages.len();
+    kids.extend(pages);
+    for _ in 0..blanks {
+        kids.push(add_page(booklet, pages_id, Vec::new(), dictionary! {})?);
+    }
+    Ok(())
+}
+
+fn add_page(
+    booklet: &mut Document,
+    pages_id: ObjectId,
+    operations: Vec<Operation>,
+    resources: lopdf::Dictionary,
+) -> Result<ObjectId, TaskPdfWriterBotError> {
+    let content = Content { operations }.encode()?;
+    let content_id = booklet.add_object(Stream::new(dictionary! {}, content));
+    Ok(booklet.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+        "Resources" => resources,
+        "Contents" => content_id,
+    }))
+}
+
+/// The standard Type1 fonts only cover WinAnsi, so anything else is replaced.
+fn encode_text(text: &str) -> Object {
+    let bytes: Vec<u8> = text
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c as u8
+            } else {
+                b'?'
+            }
+        })
+        .collect();
+    Object::string_literal(bytes)
+}
+
+fn text(x: f32, y: f32, size: f32, content: &str) -> Vec<Operation> {
+    vec![
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec!["F1".into(), size.into()]),
+        Operation::new("Td", vec![x.into(), y.into()]),
+        Operation::new("Tj", vec![encode_text(content)]),
+        Operation::new("ET", vec![]),
+    ]
+}
+
+/// Helvetica averages roughly half an em per character, which is close enough
+/// for centering titles.
+fn centered_text(y: f32, size: f32, content: &str) -> Vec<Operation> {
+    let width = content.chars().count() as f32 * size * 0.5;
+    text(((PAGE_WIDTH - width) / 2.0).max(36.0), y, size, content)
+}
+
+fn cover_page(
+    booklet: &mut Document,
+    pages_id: ObjectId,
+    font_id: ObjectId,
+    cover: &Cover,
+) -> Result<ObjectId, TaskPdfWriterBotError> {
+    let mut operations = Vec::new();
+    let mut resources = dictionary! {
+        "Font" => dictionary! { "F1" => font_id },
+    };
+    if let Some(logo) = &cover.logo {
+        let (width, height, color_space) = jpeg_info(logo)?;
+        let image_id = booklet.add_object(
+            Stream::new(
+                dictionary! {
+                    "Type" => "XObject",
+                    "Subtype" => "Image",
+                    "Width" => width as i64,
+                    "Height" => height as i64,
+                    "ColorSpace" => color_space,
+                    "BitsPerComponent" => 8,
+                    "Filter" => "DCTDecode",
+                },
+                logo.clone(),
+            )
+            .with_compression(false),
+        );
+        resources.set("XObject", dictionary! { "Logo" => image_id });
+        // Fit the logo into a 200pt box above the title.
+        let scale = (200.0 / width as f32).min(200.0 / height as f32);
+        let (w, h) = (width as f32 * scale, height as f32 * scale);
+        operations.extend([
+            Operation::new("q", vec![]),
+            Operation::new(
+                "cm",
+                vec![
+                    w.into(),
+                    0.into(),
+                    0.into(),
+                    h.into(),
+                    ((PAGE_WIDTH - w) / 2.0).into(),
+                    560.into(),
+                ],
+            ),
+            Operation::new("Do", vec!["Logo".into()]),
+            Operation::new("Q", vec![]),
+        ]);
+    }
+    operations.extend(centered_text(480.0, 28.0, &cover.title));
+    if let Some(date) = &cover.date {
+        operations.extend(centered_text(440.0, 16.0, date));
+    }
+    add_page(booklet, pages_id, operations, resources)
+}
+
+fn toc_page(
+    booklet: &mut Document,
+    pages_id: ObjectId,
+    font_id: ObjectId,
+    first: bool,
+    entries: &[(String, usize)],
+) -> Result<ObjectId, TaskPdfWriterBotError> {
+    let mut operations = Vec::new();
+    let mut y = PAGE_HEIGHT - 100.0;
+    if first {
+        operations.extend(text(72.0, y, 22.0, "Contents"));
+        y -= 40.0;
+    }
+    for (name, page) in entries {
+        operations.extend(text(72.0, y, 12.0, name));
+        operations.extend(text(PAGE_WIDTH - 108.0, y, 12.0, &page.to_string()));
+        y -= 20.0;
+    }
+    let resources = dictionary! {
+        "Font" => dictionary! { "F1" => font_id },
+    };
+    add_page(booklet, pages_id, operations, resources)
+}
+
+/// Moves every page of `source` into `booklet`, in order, under `pages_id`.
+fn import_pages(
+    booklet: &mut Document,
+    pages_id: ObjectId,
+    mut source: Document,
+) -> Result<Vec<ObjectId>, TaskPdfWriterBotError> {
+    source.renumber_objects_with(booklet.max_id + 1);
+    let page_ids: Vec<ObjectId> = source.get_pages().into_values().collect();
+    for page_id in &page_ids {
+        // Copy attributes inherited from the source page tree, which is dropped.
+        let mut inherited = Vec::new();
+        let mut parent = source
+            .get_object(*page_id)?
+            .as_dict()?
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .ok();
+        while let Some(parent_id) = parent {
+            let node = source.get_object(parent_id)?.as_dict()?;
+            for key in INHERITABLE_KEYS {
+                if let Ok(value) = node.get(key) {
+                    inherited.push((key.to_vec(), value.clone()));
+                }
+            }
+            parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+        }
+        let page = source.get_object_mut(*page_id)?.as_dict_mut()?;
+        for (key, value) in inherited {
+            if !page.has(&key) {
+                page.set(key, value);
+            }
+        }
+        page.set("Parent", pages_id);
+    }
+    for (id, object) in source.objects {
+        let skipped = match object.as_dict() {
+            Ok(dict) => dict.type_is(b"Pages") || dict.type_is(b"Catalog"),
+            Err(_) => false,
+        };
+        if !skipped {
+            booklet.objects.insert(id, object);
+        }
+    }
+    booklet.max_id = booklet.max_id.max(source.max_id);
+    Ok(page_ids)
+}
+
+/// Returns the width, height and color space of a JPEG image.
+fn jpeg_info(jpeg: &[u8]) -> Result<(u16, u16, &'static str), TaskPdfWriterBotError> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        Err(MyError::new(
+            "(probably your fault): the logo must be a JPEG image",
+        ))?;
+    }
+    let mut i = 2;
+    while i + 9 < jpeg.len() {
+        if jpeg[i] != 0xFF {
+            i += 1;
+            continue;
+        }
+        let marker = jpeg[i + 1];
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC).
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes([jpeg[i + 5], jpeg[i + 6]]);
+            let width = u16::from_be_bytes([jpeg[i + 7], jpeg[i + 8]]);
+            // A height of 0 means it is only given after the first scan.
+            if width == 0 || height == 0 {
+                Err(MyError::new(
+                    "(probably your fault): the logo must have a width and a height in its JPEG header",
+                ))?;
+            }
+            let color_space = match jpeg[i + 9] {
+                1 => "DeviceGray",
+                4 => "DeviceCMYK",
+                _ => "DeviceRGB",
+            };
+            return Ok((width, height, color_space));
+        }
+        i += 2 + length;
+    }
+    Err(MyError::new(
+        "(probably your fault): the logo is not a readable JPEG image",
+    ))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PDF of `pages` pages, each showing `<name> <n>`, whose media box and
+    /// resources are only set on the page tree, for the pages to inherit.
+    fn task_pdf(name: &str, pages: usize) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let kids: Vec<Object> = (1..=pages)
+            .map(|n| {
+                let content = Content {
+                    operations: text(72.0, 72.0, 12.0, &format!("{} {}", name, n)),
+                };
+                let content_id =
+                    doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                })
+                .into()
+            })
+            .collect();
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => pages as i64,
+                "MediaBox" => vec![0.into(), 0.into(), 300.into(), 400.into()],
+                "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        let mut out = Vec::new();
+        doc.save_to(&mut out).unwrap();
+        out
+    }
+
+    /// The strings shown on each page of `doc`, in order.
+    fn page_texts(doc: &Document) -> Vec<Vec<String>> {
+        doc.get_pages()
+            .into_values()
+            .map(|page_id| {
+                doc.get_and_decode_page_content(page_id)
+                    .unwrap()
+                    .operations
+                    .into_iter()
+                    .filter(|op| op.operator == "Tj")
+                    .map(|op| String::from_utf8(op.operands[0].as_str().unwrap().to_vec()).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The dictionary at `key`, inline or referenced.
+    fn dict<'a>(
+        doc: &'a Document,
+        parent: &'a lopdf::Dictionary,
+        key: &[u8],
+    ) -> &'a lopdf::Dictionary {
+        match parent.get(key).unwrap() {
+            Object::Reference(id) => doc.get_dictionary(*id).unwrap(),
+            object => object.as_dict().unwrap(),
+        }
+    }
+
+    /// A JPEG header up to its SOF0 segment: `width` x `height`, `components`
+    /// color channels.
+    fn jpeg_fixture(width: u16, height: u16, components: u8) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // APP0 (JFIF), skipped by the parser.
+        jpeg.extend([0xFF, 0xE0, 0x00, 0x10]);
+        jpeg.extend(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        jpeg.extend([0xFF, 0xC0, 0x00, 0x11, 0x08]);
+        jpeg.extend(height.to_be_bytes());
+        jpeg.extend(width.to_be_bytes());
+        jpeg.push(components);
+        jpeg.extend([0x01, 0x11, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01]);
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn numbers_and_pads_every_section() {
+        let cover = Cover {
+            title: "My Contest".to_string(),
+            date: Some("1 January 2023".to_string()),
+            logo: None,
+        };
+        let tasks = vec![
+            ("sum".to_string(), task_pdf("sum", 3)),
+            ("max".to_string(), task_pdf("max", 1)),
+            ("sort".to_string(), task_pdf("sort", 2)),
+        ];
+        let booklet = Document::load_mem(&build_booklet(&cover, &tasks).unwrap()).unwrap();
+        let texts = page_texts(&booklet);
+        let blank: Vec<String> = Vec::new();
+        // Cover, TOC, sum and max are padded to an even length, sort already is.
+        assert_eq!(texts.len(), 2 + 2 + 4 + 2 + 2);
+        assert_eq!(texts[0], vec!["My Contest", "1 January 2023"]);
+        assert_eq!(texts[1], blank);
+        assert_eq!(
+            texts[2],
+            vec!["Contents", "sum", "5", "max", "9", "sort", "11"]
+        );
+        assert_eq!(texts[3], blank);
+        assert_eq!(texts[4], vec!["sum 1"]);
+        assert_eq!(texts[6], vec!["sum 3"]);
+        assert_eq!(texts[7], blank);
+        assert_eq!(texts[8], vec!["max 1"]);
+        assert_eq!(texts[9], blank);
+        assert_eq!(texts[10], vec!["sort 1"]);
+        assert_eq!(texts[11], vec!["sort 2"]);
+    }
+
+    #[test]
+    fn copies_inherited_page_attributes() {
+        let cover = Cover {
+            title: "My Contest".to_string(),
+            date: None,
+            logo: Some(jpeg_fixture(64, 32, 3)),
+        };
+        let tasks = vec![("sum".to_string(), task_pdf("sum", 1))];
+        let booklet = Document::load_mem(&build_booklet(&cover, &tasks).unwrap()).unwrap();
+        let pages = booklet.get_pages();
+        let task_page = booklet.get_dictionary(pages[&5]).unwrap();
+        let media_box: Vec<f32> = task_page
+            .get(b"MediaBox")
+            .and_then(Object::as_array)
+            .unwrap()
+            .iter()
+            .map(|n| n.as_float().unwrap())
+            .collect();
+        assert_eq!(media_box, vec![0.0, 0.0, 300.0, 400.0]);
+        let resources = dict(&booklet, task_page, b"Resources");
+        let font = dict(&booklet, dict(&booklet, resources, b"Font"), b"F1");
+        assert_eq!(
+            font.get(b"BaseFont").unwrap().as_name_str().unwrap(),
+            "Courier"
+        );
+        // The cover page holds the logo, and pages the booklet adds are A4.
+        let cover_page = booklet.get_dictionary(pages[&1]).unwrap();
+        let resources = dict(&booklet, cover_page, b"Resources");
+        assert!(dict(&booklet, resources, b"XObject").has(b"Logo"));
+        assert_eq!(
+            cover_page
+                .get(b"MediaBox")
+                .and_then(Object::as_array)
+                .unwrap()[3]
+                .as_float()
+                .unwrap(),
+            PAGE_HEIGHT
+        );
+    }
+
+    #[test]
+    fn reads_the_size_of_jpeg_images() {
+        assert_eq!(
+            jpeg_info(&jpeg_fixture(64, 32, 3)).unwrap(),
+            (64, 32, "DeviceRGB")
+        );
+        assert_eq!(
+            jpeg_info(&jpeg_fixture(5, 7, 1)).unwrap(),
+            (5, 7, "DeviceGray")
+        );
+        assert!(jpeg_info(b"\x89PNG\r\n\x1a\n").is_err());
+        assert!(jpeg_info(&jpeg_fixture(64, 32, 3)[..12]).is_err());
+        assert!(jpeg_info(&jpeg_fixture(0, 32, 3)).is_err());
+        assert!(jpeg_info(&jpeg_fixture(64, 0, 3)).is_err());
+    }
+}
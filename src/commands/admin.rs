@@ -0,0 +1,205 @@
+use crate::keyring::rotate_keys;
+use crate::permissions::Capability;
+use crate::traits::{CommandHandle, CommandHandlerData, MyError, TaskPdfWriterBotError};
+
+use serenity::async_trait;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::CommandDataOptionValue;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::model::prelude::{GuildId, RoleId};
+
+pub struct AdminHandler<'a> {
+    data: &'a CommandHandlerData<'a>,
+}
+impl<'a> AdminHandler<'a> {
+    pub fn new(data: &'a CommandHandlerData<'a>) -> AdminHandler<'a> {
+        AdminHandler { data }
+    }
+    async fn run(&self) -> Result<String, TaskPdfWriterBotError> {
+        match self.data.subcommand() {
+            Some("rotate-master-key") => self.rotate_master_key().await,
+            Some("grant") => self.grant().await,
+            Some("revoke") => self.revoke().await,
+            Some("permissions") => self.permissions().await,
+            _ => Err(MyError::new("unknown subcommand"))?,
+        }
+    }
+    /// Re-encrypts every stored private key with the master key version in
+    /// `MASTER_KEY_VERSION`, after which the older versions can be removed.
+    /// This touches every guild's secrets, so only the bot's owners may.
+    async fn rotate_master_key(&self) -> Result<String, TaskPdfWriterBotError> {
+        self.data.owners.check(self.data.command.user.id)?;
+        let resealed = rotate_keys(self.data.store, self.data.keyring).await?;
+        let mut reply = format!(
+            "OK, re-encrypted {} private key(s) with master key version {}",
+            resealed.count,
+            self.data.keyring.current_version()
+        );
+        if !resealed.failures.is_empty() {
+            reply += &format!(
+                "\nCould not re-encrypt {} secret(s), keep the older master keys until they are fixed or deleted:\n{}",
+                resealed.failures.len(),
+                resealed.failures.join("\n")
+            );
+        }
+        Ok(reply)
+    }
+    /// The guild, capability and role given to `grant` and `revoke`.
+    fn grant_options(&self) -> Result<(GuildId, Capability, RoleId), TaskPdfWriterBotError> {
+        let guild_id = match self.data.command.guild_id {
+            Some(g) => g,
+            None => Err(MyError::new("guild_id not found"))?,
+        };
+        let capability = match self
+            .data
+            .string_option("capability")
+            .and_then(Capability::parse)
+        {
+            Some(c) => c,
+            None => Err(MyError::new("(probably your fault): invalid capability"))?,
+        };
+        let role_id = match self.data.option("role") {
+            Some(CommandDataOptionValue::Role(role)) => role.id,
+            _ => Err(MyError::new("(probably your fault): role not found"))?,
+        };
+        Ok((guild_id, capability, role_id))
+    }
+    async fn grant(&self) -> Result<String, TaskPdfWriterBotError> {
+        let (guild_id, capability, role_id) = self.grant_options()?;
+        match self.data.store.grant(guild_id, capability, role_id).await? {
+            true => Ok(format!(
+                "OK, <@&{}> may {}. Only members with a granted role may {} now.",
+                role_id,
+                capability.name(),
+                capability.name()
+            )),
+            false => Ok(format!("<@&{}> may already {}", role_id, capability.name())),
+        }
+    }
+    async fn revoke(&self) -> Result<String, TaskPdfWriterBotError> {
+        let (guild_id, capability, role_id) = self.grant_options()?;
+        match self
+            .data
+            .store
+            .revoke(guild_id, capability, role_id)
+            .await?
+        {
+            true => Ok(format!(
+                "OK, <@&{}> may no longer {}",
+                role_id,
+                capability.name()
+            )),
+            false => Ok(format!(
+                "<@&{}> was not granted {}",
+                role_id,
+                capability.name()
+            )),
+        }
+    }
+    /// Lists who may do what in this guild.
+    async fn permissions(&self) -> Result<String, TaskPdfWriterBotError> {
+        let guild_id = match self.data.command.guild_id {
+            Some(g) => g,
+            None => Err(MyError::new("guild_id not found"))?,
+        };
+        let mut lines = Vec::new();
+        for capability in Capability::ALL {
+            let granted = self.data.store.granted_roles(guild_id, capability).await?;
+            let who = match (
+                granted.is_empty(),
+                capability.default_permissions().is_empty(),
+            ) {
+                (false, _) => granted
+                    .iter()
+                    .map(|r| format!("<@&{}>", r))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                (true, true) => "everyone".to_string(),
+                (true, false) => format!(
+                    "members with the {} permission",
+                    capability.default_permissions()
+                ),
+            };
+            lines.push(format!("{}: {}", capability.name(), who));
+        }
+        lines.push("Administrators may always do everything.".to_string());
+        Ok(lines.join("\n"))
+    }
+}
+
+fn capability_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("capability")
+        .description("What the role may do")
+        .kind(CommandOptionType::String)
+        .required(true);
+    for capability in Capability::ALL {
+        option.add_string_choice(capability.name(), capability.name());
+    }
+    option
+}
+
+fn role_option(option: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    option
+        .name("role")
+        .description("Role")
+        .kind(CommandOptionType::Role)
+        .required(true)
+}
+
+#[async_trait]
+impl<'a> CommandHandle<'a> for AdminHandler<'a> {
+    fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        command
+            .name("admin")
+            .description("Maintenance of the bot's deployment")
+            .create_option(|subcommand| {
+                subcommand
+                    .name("rotate-master-key")
+                    .description("Re-encrypts the stored private keys with the current master key (bot owners only)")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("grant")
+                    .description("Allows a role to configure, generate or administer")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(capability_option)
+                    .create_sub_option(role_option)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("revoke")
+                    .description("Takes a capability back from a role")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(capability_option)
+                    .create_sub_option(role_option)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("permissions")
+                    .description("Lists which roles may configure, generate or administer")
+                    .kind(CommandOptionType::SubCommand)
+            })
+    }
+    async fn handle(&'a self) -> Result<(), TaskPdfWriterBotError> {
+        self.data
+            .command
+            .create_interaction_response(&self.data.ctx.http, |response| {
+                response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+        let retst = match self.run().await {
+            Ok(s) => s,
+            Err(e) => e.to_string(),
+        };
+        self.data
+            .command
+            .create_followup_message(&self.data.ctx.http, |response| response.content(retst))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::booklet::{build_booklet, Cover};
+use crate::commands::genpdf::{
+    check_upload_size, list_tasks, prepare_contest, ALL_LANGUAGES, MAX_CONCURRENT_RENDERS,
+};
+use crate::contests;
+use crate::traits::{CommandHandle, CommandHandlerData, MyError, TaskPdfWriterBotError};
+use crate::util::safe_join;
+
+use futures::stream::{self, StreamExt};
+use git2::Oid;
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::channel::AttachmentType;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use std::borrow::Cow;
+use std::fs;
+
+pub struct BookletHandler<'a> {
+    data: &'a CommandHandlerData<'a>,
+}
+impl<'a> BookletHandler<'a> {
+    pub fn new(data: &'a CommandHandlerData<'a>) -> BookletHandler<'a> {
+        BookletHandler { data }
+    }
+    /// Uses the `booklet` section of `config.json` (see [`crate::contest_config::BookletConfig`]).
+    /// With `lang`, `config.<lang>.json` applies on top.
+    async fn run(&'a self) -> Result<(Oid, Vec<u8>), TaskPdfWriterBotError> {
+        let requested_lang = self.data.string_option("lang");
+        if requested_lang == Some(ALL_LANGUAGES) {
+            Err(MyError::new(
+                "(probably your fault): a booklet is in one language, pick a language code",
+            ))?;
+        }
+        let contest = contests::resolve(self.data).await?;
+        let prepared = prepare_contest(self.data, &contest).await?;
+        let settings = prepared.config_for(requested_lang)?.booklet();
+        let tasks = match settings.tasks {
+            Some(tasks) => tasks,
+            None => list_tasks(&prepared.contest_dir)?,
+        };
+        if tasks.is_empty() {
+            Err(MyError::new("no tasks to put in the booklet"))?;
+        }
+        let logo = match settings.logo {
+            Some(logo_path) => Some(fs::read(safe_join(&prepared.contest_dir, logo_path)?)?),
+            None => None,
+        };
+        let cover = Cover {
+            title: settings.title.unwrap_or_default(),
+            date: settings.date,
+            logo,
+        };
+
+        let mut jobs = Vec::new();
+        for task in tasks {
+            let lang = prepared
+                .languages_for(&task, requested_lang)?
+                .into_iter()
+                .next()
+                .flatten();
+            jobs.push((task, lang));
+        }
+        let prepared = &prepared;
+        let results: Vec<_> = stream::iter(jobs)
+            .map(|(name, lang)| async move {
+                let result = prepared
+                    .render_task(self.data, name.clone(), lang.as_deref())
+                    .await;
+                (name, result)
+            })
+            .buffered(MAX_CONCURRENT_RENDERS)
+            .collect()
+            .await;
+        let mut pdfs = Vec::new();
+        let mut failures = Vec::new();
+        for (name, result) in results {
+            match result {
+                Ok(file) => pdfs.push((name, fs::read(&file)?)),
+                Err(e) => failures.push(format!("{}: {}", name, e)),
+            }
+        }
+        if !failures.is_empty() {
+            Err(MyError::new(&format!(
+                "cannot render every task of the booklet\n{}",
+                failures.join("\n")
+            )))?;
+        }
+        Ok((prepared.commit, build_booklet(&cover, &pdfs)?))
+    }
+}
+
+#[async_trait]
+impl<'a> CommandHandle<'a> for BookletHandler<'a> {
+    fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        command
+            .name("booklet")
+            .description("Generates one PDF with a cover page, a table of contents and every task")
+            .create_option(|option| {
+                option
+                    .name("lang")
+                    .description("Language code of the statements, e.g. th for <task>.th.md")
+                    .kind(CommandOptionType::String)
+                    .required(false)
+            })
+            .create_option(|option| {
+                option
+                    .name("ref")
+                    .description("Branch, tag or commit to generate from (defaults to the configured branch)")
+                    .kind(CommandOptionType::String)
+                    .required(false)
+            })
+    }
+    async fn handle(&'a self) -> Result<(), TaskPdfWriterBotError> {
+        self.data
+            .command
+            .create_interaction_response(&self.data.ctx.http, |response| {
+                response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+        let rendered = self.run().await.and_then(|(commit, pdf)| {
+            check_upload_size("booklet.pdf", pdf.len() as u64)?;
+            Ok((commit, pdf))
+        });
+        match rendered {
+            Ok((commit, pdf)) => {
+                self.data
+                    .command
+                    .create_followup_message(&self.data.ctx.http, |response| {
+                        response
+                            .content(format!("Rendered at commit `{}`.", commit))
+                            .add_file(AttachmentType::Bytes {
+                                data: Cow::Owned(pdf),
+                                filename: "booklet.pdf".to_string(),
+                            })
+                    })
+                    .await?;
+            }
+            Err(e) => {
+                self.data
+                    .command
+                    .create_followup_message(&self.data.ctx.http, |response| {
+                        response.content(format!("{:?}", e))
+                    })
+                    .await?;
+            }
+        };
+        Ok(())
+    }
+}
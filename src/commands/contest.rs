@@ -0,0 +1,218 @@
+use crate::contests::{self, ContestRef, DEFAULT_CONTEST};
+use crate::store::ChangeAuthor;
+use crate::traits::{CommandHandle, CommandHandlerData, MyError, TaskPdfWriterBotError};
+
+use serenity::async_trait;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::CommandDataOptionValue;
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::model::prelude::{ChannelId, ChannelType};
+
+pub struct ContestHandler<'a> {
+    data: &'a CommandHandlerData<'a>,
+}
+impl<'a> ContestHandler<'a> {
+    pub fn new(data: &'a CommandHandlerData<'a>) -> ContestHandler<'a> {
+        ContestHandler { data }
+    }
+    async fn run(&self) -> Result<String, TaskPdfWriterBotError> {
+        match self.data.subcommand() {
+            Some("create") => self.create().await,
+            Some("list") => self.list().await,
+            Some("use") => self.use_contest().await,
+            Some("bind") => self.bind().await,
+            _ => Err(MyError::new("unknown subcommand"))?,
+        }
+    }
+    /// The contest named by the `name` option.
+    fn named(&self) -> Result<ContestRef, TaskPdfWriterBotError> {
+        let guild_id = match self.data.command.guild_id {
+            Some(g) => g,
+            None => Err(MyError::new("guild_id not found"))?,
+        };
+        let name = match self.data.string_option("name") {
+            Some(n) => n.trim().to_string(),
+            None => Err(MyError::new("(probably your fault): name not found"))?,
+        };
+        Ok(ContestRef { guild_id, name })
+    }
+    fn channel(&self) -> Option<ChannelId> {
+        match self.data.option("channel") {
+            Some(CommandDataOptionValue::Channel(c)) => Some(c.id),
+            _ => None,
+        }
+    }
+    /// Creates a contest as a copy of the one this channel uses, and makes it
+    /// the active contest so `/config` changes it next.
+    async fn create(&self) -> Result<String, TaskPdfWriterBotError> {
+        let name = self.named()?.name;
+        let from = contests::resolve(self.data).await?;
+        let channel_id = self.channel();
+        let changed_by = self.data.command.user.id.to_string();
+        let author = ChangeAuthor {
+            changed_by: &changed_by,
+            changed_by_name: &self.data.command.user.name,
+            action: "contest create",
+        };
+        let created = contests::create(self.data.store, &from, &name, channel_id, author).await?;
+        self.data.store.set_active_contest(&created).await?;
+        let bound = match channel_id {
+            Some(c) => format!(", bound to <#{}>", c),
+            None => String::new(),
+        };
+        Ok(format!(
+            "OK, the contest {} is created as a copy of {}{} and is now the active contest. Change what differs with `/config`.",
+            created.name, from.name, bound
+        ))
+    }
+    async fn list(&self) -> Result<String, TaskPdfWriterBotError> {
+        let here = contests::resolve(self.data).await?;
+        let active = self
+            .data
+            .store
+            .active_contest(here.guild_id)
+            .await?
+            .unwrap_or_else(|| DEFAULT_CONTEST.to_string());
+        let listed = self.data.store.contests(here.guild_id).await?;
+        if listed.is_empty() {
+            return Ok("No contest is configured yet, start with `/config set`".to_string());
+        }
+        let lines: Vec<String> = listed
+            .iter()
+            .map(|contest| {
+                let mut line = format!(
+                    "{}: {} `{}`",
+                    contest.name, contest.git_remote_url, contest.contest_rel_path
+                );
+                if let Some(c) = &contest.channel_id {
+                    line += &format!(", bound to <#{}>", c);
+                }
+                if contest.name == active {
+                    line += " (active)";
+                }
+                if contest.name == here.name {
+                    line += " (used here)";
+                }
+                line
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+    /// Makes a contest the active one, used in every channel that is not
+    /// bound to a contest.
+    async fn use_contest(&self) -> Result<String, TaskPdfWriterBotError> {
+        let contest = self.named()?;
+        if self.data.store.contest(&contest).await?.is_none() {
+            Err(MyError::new(&format!(
+                "(probably your fault): there is no contest {}, see `/contest list`",
+                contest.name
+            )))?;
+        }
+        self.data.store.set_active_contest(&contest).await?;
+        let here = contests::resolve(self.data).await?;
+        match here.name == contest.name {
+            true => Ok(format!("OK, the active contest is {}", contest.name)),
+            false => Ok(format!(
+                "OK, the active contest is {}, but this channel is bound to {}",
+                contest.name, here.name
+            )),
+        }
+    }
+    /// Binds a contest to a channel or category, so commands run there (and in
+    /// its threads and channels) use it. Without `channel`, unbinds it.
+    async fn bind(&self) -> Result<String, TaskPdfWriterBotError> {
+        let contest = self.named()?;
+        let channel_id = self.channel();
+        if !contests::bind(self.data.store, &contest, channel_id).await? {
+            Err(MyError::new(&format!(
+                "(probably your fault): there is no contest {}, see `/contest list`",
+                contest.name
+            )))?;
+        }
+        match channel_id {
+            Some(c) => Ok(format!(
+                "OK, commands in <#{}> use the contest {}",
+                c, contest.name
+            )),
+            None => Ok(format!(
+                "OK, the contest {} is not bound anymore",
+                contest.name
+            )),
+        }
+    }
+}
+
+fn name_option(option: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    option
+        .name("name")
+        .description("Name of the contest, e.g. qualifier")
+        .kind(CommandOptionType::String)
+        .required(true)
+}
+
+fn channel_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("channel")
+        .description("Channel or category whose commands use the contest")
+        .kind(CommandOptionType::Channel)
+        .channel_types(&[ChannelType::Text, ChannelType::Category])
+        .required(false)
+}
+
+#[async_trait]
+impl<'a> CommandHandle<'a> for ContestHandler<'a> {
+    fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        command
+            .name("contest")
+            .description("Manages the contests of this server")
+            .create_option(|subcommand| {
+                subcommand
+                    .name("create")
+                    .description("Creates a contest as a copy of the one used here")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(name_option)
+                    .create_sub_option(channel_option)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("list")
+                    .description("Lists the contests of this server")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("use")
+                    .description("Switches the active contest, used in channels not bound to one")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(name_option)
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("bind")
+                    .description("Binds a contest to a channel or category (leave blank to unbind)")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(name_option)
+                    .create_sub_option(channel_option)
+            })
+    }
+    async fn handle(&'a self) -> Result<(), TaskPdfWriterBotError> {
+        self.data
+            .command
+            .create_interaction_response(&self.data.ctx.http, |response| {
+                response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+        let retst = match self.run().await {
+            Ok(s) => s,
+            Err(e) => e.to_string(),
+        };
+        self.data
+            .command
+            .create_followup_message(&self.data.ctx.http, |response| response.content(retst))
+            .await?;
+        Ok(())
+    }
+}
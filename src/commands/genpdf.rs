@@ -1,67 +1,486 @@
+use crate::contest_config::{ContestConfig, RenderRequest};
+use crate::contests::{self, ContestRef};
+use crate::credentials::Credentials;
+use crate::known_hosts::{HostKey, HostKeyCheck};
+use crate::pdf_cache::cache_key;
+use crate::renderer::{HttpRenderer, PdfRenderer};
+use crate::store::ContestStore;
 use crate::traits::{CommandHandle, CommandHandlerData, MyError, TaskPdfWriterBotError};
-use crate::util::{get_metadata, get_name, prep_repo};
+use crate::util::{get_metadata, get_name, safe_join, Contest};
+use crate::workdir::WorkDir;
 
-use base64::{engine::general_purpose, Engine};
 use serenity::async_trait;
 use serenity::builder::CreateApplicationCommand;
+use serenity::model::channel::AttachmentType;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::CommandDataOptionValue;
 use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::model::prelude::GuildId;
 
-use git2::Repository;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use std::{env, fs};
+use futures::stream::{self, StreamExt};
+use git2::Oid;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
 
-fn retrieve_config(
-    repo: &Repository,
-    reldir: String,
+/// How many tasks `/genpdf all` and `/booklet` render at the same time.
+pub(crate) const MAX_CONCURRENT_RENDERS: usize = 4;
+/// Discord refuses messages with more attachments than this, so larger batches
+/// are sent as zip files.
+const MAX_ATTACHMENTS: usize = 10;
+/// How many bytes of attachments Discord accepts per message in a guild
+/// without boosts. Batches that are larger are split across messages.
+const MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+/// Room for the headers of each file in a zip, on top of its size.
+const ZIP_ENTRY_OVERHEAD: u64 = 1024;
+
+fn read_json(path: &Path) -> Result<serde_json::Value, TaskPdfWriterBotError> {
+    let json_string = fs::read_to_string(path)?;
+    match serde_json::from_str(json_string.as_str()) {
+        Ok(value) => Ok(value),
+        Err(e) => Err(MyError::new(&format!(
+            "(probably your fault): {}: {}",
+            file_name(path),
+            e
+        )))?,
+    }
+}
+
+pub(crate) fn retrieve_config(
+    contest_dir: &Path,
 ) -> Result<serde_json::Value, TaskPdfWriterBotError> {
-    let current_path = repo.path().join("..").join(reldir).join("config.json");
-    println!("{}", current_path.display());
-    let json_string = fs::read_to_string(current_path)?;
-    Ok(serde_json::from_str(json_string.as_str())?)
-}
-
-async fn generate_pdf(
-    task_name: String,
-    task_content: String,
-    mut config_json: serde_json::Value,
-) -> Result<PathBuf, TaskPdfWriterBotError> {
-    let mut hasher = DefaultHasher::new();
-    (task_name.clone() + task_content.as_str()).hash(&mut hasher);
-    let hashed_out = hasher.finish();
-    let outfile_path = env::temp_dir().join(hashed_out.to_string() + ".pdf");
-    config_json["content"] = serde_json::Value::String(task_content);
-    config_json["task_name"] = serde_json::Value::String(task_name);
-    let client = reqwest::Client::new();
-    let resp = client
-        .post("https://973i5k6wjg.execute-api.ap-southeast-1.amazonaws.com/dev/genpdf")
-        .body(config_json.to_string())
-        .send()
-        .await?
-        .text()
-        .await?;
-    let resp_obj: serde_json::Value = serde_json::from_str(resp.as_str())?;
-    let resp_obj = match resp_obj.as_object() {
-        Some(obj) => obj,
-        None => Err(MyError::new("JSON object not found"))?,
+    let current_path = safe_join(contest_dir, "config.json")?;
+    read_json(&current_path)
+}
+
+/// The request the renderer gets for one task, also what the PDF cache key is
+/// computed from.
+fn render_request(
+    task_name: &str,
+    task_content: &str,
+    config: &ContestConfig,
+) -> Result<serde_json::Value, TaskPdfWriterBotError> {
+    let request = RenderRequest {
+        config,
+        task_name,
+        content: task_content,
     };
-    let message = match resp_obj.get("message") {
-        Some(m) => m,
-        None => Err(MyError::new("message doesn't exist"))?,
+    Ok(serde_json::to_value(request)?)
+}
+
+/// The `lang` value that asks for every available language.
+pub(crate) const ALL_LANGUAGES: &str = "all";
+
+/// The ISO 639-1 language codes, sorted.
+const LANGUAGE_CODES: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bh",
+    "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da",
+    "de", "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj",
+    "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb",
+    "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi",
+    "pl", "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti",
+    "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo",
+    "wa", "wo", "xh", "yi", "yo", "za", "zh", "zu",
+];
+
+/// Whether `s` is an ISO 639-1 language code, optionally with a region such
+/// as `pt-BR`.
+// `Option::is_none_or` needs Rust 1.82.
+#[allow(clippy::unnecessary_map_or)]
+fn is_language_code(s: &str) -> bool {
+    let (primary, region) = match s.split_once('-') {
+        Some((primary, region)) => (primary, Some(region)),
+        None => (s, None),
     };
-    if let serde_json::Value::String(content_base64) = message {
-        let decoded_content = match general_purpose::STANDARD.decode(content_base64) {
+    LANGUAGE_CODES.binary_search(&primary).is_ok()
+        && region.map_or(true, |r| {
+            !r.is_empty() && r.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Splits a markdown file stem into the task name and its language, so `task.th`
+/// is the Thai statement of `task` while `task` and `task.old` have no language
+/// suffix. Besides ISO 639-1 codes, the suffix may be any language the contest
+/// has a `config.<lang>.json` for.
+fn split_language<'s>(stem: &'s str, configured: &[String]) -> (&'s str, Option<&'s str>) {
+    match stem.rsplit_once('.') {
+        Some((task, lang))
+            if !task.is_empty()
+                && (is_language_code(lang) || configured.iter().any(|c| c == lang)) =>
+        {
+            (task, Some(lang))
+        }
+        _ => (stem, None),
+    }
+}
+
+/// The languages the contest directory has a `config.<lang>.json` for.
+fn configured_languages(contest_dir: &Path) -> Result<Vec<String>, TaskPdfWriterBotError> {
+    let mut languages = Vec::new();
+    for entry in contest_dir.read_dir()? {
+        let name = entry?.file_name();
+        let lang = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("config."))
+            .and_then(|n| n.strip_suffix(".json"));
+        if let Some(lang) = lang.filter(|l| !l.is_empty() && !l.contains('.')) {
+            languages.push(lang.to_string());
+        }
+    }
+    Ok(languages)
+}
+
+/// The stems of every markdown file in the contest directory.
+fn markdown_stems(contest_dir: &Path) -> Result<Vec<String>, TaskPdfWriterBotError> {
+    let mut stems = Vec::new();
+    for entry in contest_dir.read_dir()? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("md") {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            stems.push(stem.to_string());
+        }
+    }
+    Ok(stems)
+}
+
+pub(crate) fn list_tasks(contest_dir: &Path) -> Result<Vec<String>, TaskPdfWriterBotError> {
+    let configured = configured_languages(contest_dir)?;
+    let tasks: BTreeSet<String> = markdown_stems(contest_dir)?
+        .iter()
+        .map(|stem| split_language(stem, &configured).0.to_string())
+        .collect();
+    Ok(tasks.into_iter().collect())
+}
+
+/// Every language a task is written in, `None` standing for `<task>.md`.
+pub(crate) fn task_languages(
+    contest_dir: &Path,
+    task: &str,
+) -> Result<Vec<Option<String>>, TaskPdfWriterBotError> {
+    let configured = configured_languages(contest_dir)?;
+    let mut languages: Vec<Option<String>> = markdown_stems(contest_dir)?
+        .iter()
+        .map(|stem| split_language(stem, &configured))
+        .filter(|(t, _)| *t == task)
+        .map(|(_, lang)| lang.map(|l| l.to_string()))
+        .collect();
+    languages.sort();
+    Ok(languages)
+}
+
+/// How a task and language are named in replies and file names: `task` or `task.th`.
+pub(crate) fn task_label(task: &str, lang: Option<&str>) -> String {
+    match lang {
+        Some(l) => format!("{}.{}", task, l),
+        None => task.to_string(),
+    }
+}
+
+/// Recursively overlays `overlay` onto `base`: objects are merged key by key,
+/// anything else is replaced.
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge_json(base.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn mebibytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Refuses a file larger than Discord accepts, naming it and its size.
+pub(crate) fn check_upload_size(name: &str, size: u64) -> Result<(), TaskPdfWriterBotError> {
+    if size > MAX_UPLOAD_BYTES {
+        Err(MyError::new(&format!(
+            "{} is {}, more than the {} Discord accepts in a message",
+            name,
+            mebibytes(size),
+            mebibytes(MAX_UPLOAD_BYTES)
+        )))?;
+    }
+    Ok(())
+}
+
+/// Splits `items`, in order, into groups of at most `max_count` items whose
+/// sizes add up to at most [`MAX_UPLOAD_BYTES`]. An item larger than that on
+/// its own gets a group of its own.
+fn split_uploads<T>(items: Vec<(T, u64)>, max_count: usize) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = Vec::new();
+    let mut group_size = 0;
+    for (item, size) in items {
+        match groups.last_mut() {
+            Some(group) if group.len() < max_count && group_size + size <= MAX_UPLOAD_BYTES => {
+                group.push(item);
+                group_size += size;
+            }
+            _ => {
+                groups.push(vec![item]);
+                group_size = size;
+            }
+        }
+    }
+    groups
+}
+
+fn zip_pdfs(pdfs: &[PathBuf]) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for path in pdfs {
+        let started = zip.start_file(file_name(path), zip::write::FileOptions::default());
+        if let Err(e) = started {
+            Err(MyError::new(&format!("[zip] {}", e)))?;
+        }
+        zip.write_all(&fs::read(path)?)?;
+    }
+    match zip.finish() {
+        Ok(cursor) => Ok(cursor.into_inner()),
+        Err(e) => Err(MyError::new(&format!("[zip] {}", e)))?,
+    }
+}
+
+/// Each task label with its rendered PDF or the reason it failed.
+type TaskResults = Vec<(String, Result<PathBuf, TaskPdfWriterBotError>)>;
+
+/// Everything needed to render tasks of the guild's configured contest.
+pub(crate) struct PreparedContest {
+    pub(crate) contest_dir: PathBuf,
+    /// `config.json` as read, use [`PreparedContest::config_for`] to get the
+    /// validated config for a language.
+    config_json: serde_json::Value,
+    /// The commit the contest was taken from.
+    pub(crate) commit: Oid,
+    guild_id: GuildId,
+    default_lang: Option<String>,
+    guild_renderer: Option<HttpRenderer>,
+    /// Holds the contest snapshot and the rendered PDFs, removed on drop.
+    job: WorkDir,
+}
+impl PreparedContest {
+    fn renderer<'b>(&'b self, default: &'b dyn PdfRenderer) -> &'b dyn PdfRenderer {
+        // A guild-specific endpoint takes precedence over the deployment's renderer.
+        match &self.guild_renderer {
+            Some(r) => r,
+            None => default,
+        }
+    }
+    /// The contest config for a language: `config.json` overlaid with
+    /// `config.<lang>.json` when there is one.
+    pub(crate) fn config_for(
+        &self,
+        lang: Option<&str>,
+    ) -> Result<ContestConfig, TaskPdfWriterBotError> {
+        let mut config_json = self.config_json.clone();
+        let mut source = "config.json".to_string();
+        if let Some(l) = lang {
+            let override_path = safe_join(&self.contest_dir, format!("config.{}.json", l))?;
+            if override_path.is_file() {
+                merge_json(&mut config_json, read_json(&override_path)?);
+                source += &format!(" + config.{}.json", l);
+            }
+        }
+        ContestConfig::parse(config_json, &source)
+    }
+    /// The languages to render `task` in. `requested` is the `lang` option:
+    /// a language code, [`ALL_LANGUAGES`], or `None` for the guild's default
+    /// language, falling back to `<task>.md` if the task isn't translated.
+    pub(crate) fn languages_for(
+        &self,
+        task: &str,
+        requested: Option<&str>,
+    ) -> Result<Vec<Option<String>>, TaskPdfWriterBotError> {
+        match requested {
+            Some(ALL_LANGUAGES) => {
+                let available = task_languages(&self.contest_dir, task)?;
+                if available.is_empty() {
+                    Err(MyError::new("file not found"))?;
+                }
+                Ok(available)
+            }
+            Some(lang) => Ok(vec![Some(lang.to_string())]),
+            None => match &self.default_lang {
+                Some(lang)
+                    if safe_join(&self.contest_dir, task_label(task, Some(lang)) + ".md")?
+                        .is_file() =>
+                {
+                    Ok(vec![Some(lang.clone())])
+                }
+                _ => Ok(vec![None]),
+            },
+        }
+    }
+    /// Renders a task in a language, or takes it from the PDF cache when neither
+    /// its markdown, the config nor the renderer changed.
+    pub(crate) async fn render_task(
+        &self,
+        data: &CommandHandlerData<'_>,
+        name: String,
+        lang: Option<&str>,
+    ) -> Result<PathBuf, TaskPdfWriterBotError> {
+        let label = task_label(&name, lang);
+        // The task name comes from the thread name, keep it inside the snapshot.
+        let md_path = safe_join(&self.contest_dir, label.clone() + ".md")?;
+        if !md_path.is_file() {
+            Err(MyError::new("file not found"))?;
+        }
+        let file_content = String::from_utf8_lossy(&fs::read(md_path)?).to_string();
+        let config = self.config_for(lang)?;
+        let renderer = self.renderer(data.renderer);
+        let identity = renderer.identity();
+        let request = render_request(&name, &file_content, &config)?;
+        let key = cache_key(&request, &identity);
+        let pdf = match data.pdf_cache.get(&key).await? {
+            Some(pdf) => {
+                info!("{} served from the PDF cache ({})", label, key);
+                pdf
+            }
+            None => {
+                let pdf = renderer.render(&request).await?;
+                data.pdf_cache
+                    .put(&key, self.guild_id, &label, self.commit, &identity, &pdf)
+                    .await?;
+                pdf
+            }
+        };
+        let outfile_path = safe_join(self.job.path(), label + ".pdf")?;
+        if let Some(parent) = outfile_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&outfile_path, pdf)?;
+        Ok(outfile_path)
+    }
+}
+
+/// A contest of the guild, with its remote checked against the policy and
+/// its secret decrypted, ready to be fetched.
+pub(crate) struct ContestSource {
+    pub(crate) guild_id: GuildId,
+    pub(crate) contest: Contest,
+    pub(crate) credentials: Credentials,
+    pub(crate) host_check: Arc<HostKeyCheck>,
+}
+impl ContestSource {
+    pub(crate) async fn load(
+        data: &CommandHandlerData<'_>,
+        contest: &ContestRef,
+    ) -> Result<ContestSource, TaskPdfWriterBotError> {
+        let guild_id = contest.guild_id;
+        let contest = get_metadata(contest, data.store).await?;
+        let remote = data.remote_policy.check(&contest.git_remote_url).await?;
+        let known_host_key = match remote.scheme.as_str() {
+            "ssh" => data
+                .store
+                .known_host(guild_id, &remote)
+                .await?
+                .map(|h| h.key),
+            _ => None,
+        };
+        let credentials =
+            Credentials::for_contest(&contest, &remote, &guild_id.to_string(), data.keyring)?;
+        Ok(ContestSource {
+            guild_id,
+            contest,
+            credentials,
+            host_check: Arc::new(HostKeyCheck::new(remote, known_host_key)),
+        })
+    }
+    /// The `ref` option, or the contest's default branch.
+    pub(crate) fn reference(&self, data: &CommandHandlerData<'_>) -> Option<String> {
+        data.string_option("ref")
+            .map(|r| r.to_string())
+            .or_else(|| self.contest.default_branch.clone())
+    }
+    /// Updates the contest's cached repository and copies the contest directory
+    /// at `reference` into a fresh work directory, under `contest/`.
+    pub(crate) async fn snapshot(
+        &self,
+        data: &CommandHandlerData<'_>,
+        reference: Option<String>,
+    ) -> Result<(WorkDir, Oid), TaskPdfWriterBotError> {
+        let snapshot = data
+            .repo_cache
+            .snapshot(
+                self.guild_id,
+                self.contest.git_remote_url.clone(),
+                self.credentials.clone(),
+                reference,
+                self.contest.contest_rel_path.clone(),
+                self.host_check.clone(),
+            )
+            .await;
+        let snapshot = match snapshot {
             Ok(s) => s,
-            Err(e) => Err(MyError::new(
-                ("[base64]".to_string() + e.to_string().as_str()).as_str(),
-            ))?,
+            // Show a host key mismatch as the warning it is, not as a git error.
+            Err(TaskPdfWriterBotError::GitError(e)) if e.code() == git2::ErrorCode::Certificate => {
+                Err(MyError::new(e.message()))?
+            }
+            Err(e) => Err(e)?,
         };
-        fs::write(&outfile_path, decoded_content)?;
-    } else {
-        Err(MyError::new("message in json is not a string"))?;
+        self.remember_host_key(data.store).await?;
+        Ok(snapshot)
+    }
+    /// Stores the host key accepted on first use by the last connection, and
+    /// returns it.
+    pub(crate) async fn remember_host_key(
+        &self,
+        store: &dyn ContestStore,
+    ) -> Result<Option<HostKey>, TaskPdfWriterBotError> {
+        let key = self.host_check.first_seen();
+        if let Some(key) = &key {
+            store
+                .remember_host(self.guild_id, self.host_check.remote(), key, false)
+                .await?;
+        }
+        Ok(key)
     }
-    Ok(outfile_path)
+}
+
+/// Updates the contest's cached repository and loads its `config.json` at the
+/// commit given by the `ref` option, or the contest's default branch.
+pub(crate) async fn prepare_contest(
+    data: &CommandHandlerData<'_>,
+    contest: &ContestRef,
+) -> Result<PreparedContest, TaskPdfWriterBotError> {
+    let source = ContestSource::load(data, contest).await?;
+    let (job, commit) = source.snapshot(data, source.reference(data)).await?;
+    let contest_dir = job.path().join("contest");
+    let config_json = retrieve_config(&contest_dir)?;
+    // Checked again on every render, the host may resolve elsewhere by now.
+    let guild_renderer = match source.contest.renderer_url {
+        Some(url) => {
+            let (host, addresses) = data.remote_policy.check_renderer(&url).await?;
+            Some(HttpRenderer::pinned(url, &host, &addresses)?)
+        }
+        None => None,
+    };
+    Ok(PreparedContest {
+        contest_dir,
+        config_json,
+        commit,
+        guild_id: source.guild_id,
+        default_lang: source.contest.default_lang,
+        guild_renderer,
+        job,
+    })
 }
 
 pub struct GenpdfHandler<'a> {
@@ -71,25 +490,162 @@ impl<'a> GenpdfHandler<'a> {
     pub fn new(data: &'a CommandHandlerData<'a>) -> GenpdfHandler<'a> {
         GenpdfHandler { data }
     }
-    async fn run(&'a self) -> Result<PathBuf, TaskPdfWriterBotError> {
-        let name = get_name(self.data.command.channel_id, &self.data.ctx).await?;
-        let guild_id = match self.data.command.guild_id {
-            Some(g) => g,
-            None => Err(MyError::new("guild_id not found"))?,
+    fn wants_all(&self) -> bool {
+        matches!(
+            self.data.option("all"),
+            Some(CommandDataOptionValue::Boolean(true))
+        )
+    }
+    /// Renders this thread's task. The PDF lives in the returned job's work
+    /// directory.
+    async fn run(&'a self) -> Result<(PreparedContest, PathBuf), TaskPdfWriterBotError> {
+        let name = get_name(self.data.command.channel_id, self.data.ctx).await?;
+        let contest = contests::resolve(self.data).await?;
+        let prepared = prepare_contest(self.data, &contest).await?;
+        let lang = prepared
+            .languages_for(&name, self.data.string_option("lang"))?
+            .into_iter()
+            .next()
+            .flatten();
+        let file = prepared
+            .render_task(self.data, name, lang.as_deref())
+            .await?;
+        Ok((prepared, file))
+    }
+    /// Renders every task of the contest (or just this thread's task), in every
+    /// requested language, returning each with either its PDF or the reason it
+    /// failed.
+    async fn run_batch(
+        &'a self,
+        all_tasks: bool,
+    ) -> Result<(PreparedContest, TaskResults), TaskPdfWriterBotError> {
+        let contest = contests::resolve(self.data).await?;
+        let prepared = prepare_contest(self.data, &contest).await?;
+        let tasks = if all_tasks {
+            list_tasks(&prepared.contest_dir)?
+        } else {
+            vec![get_name(self.data.command.channel_id, self.data.ctx).await?]
         };
-        let (url, reldir, privkey) = get_metadata(guild_id, self.data.database).await?;
-        let repo = prep_repo(guild_id, url, privkey).await?;
-        let config_json = retrieve_config(&repo, reldir.to_owned())?;
-        let md_path = repo
-            .path()
-            .join("..")
-            .join(reldir)
-            .join(name.clone() + ".md");
-        if !md_path.is_file() {
-            Err(MyError::new("file not found"))?;
+        if tasks.is_empty() {
+            Err(MyError::new(
+                "no markdown files found in the contest directory",
+            ))?;
         }
-        let file_content = String::from_utf8_lossy(&fs::read(md_path)?).to_string();
-        generate_pdf(name.clone(), file_content, config_json.clone()).await
+        let mut jobs = Vec::new();
+        for task in tasks {
+            for lang in prepared.languages_for(&task, self.data.string_option("lang"))? {
+                jobs.push((task.clone(), lang));
+            }
+        }
+        let job = &prepared;
+        let mut results: Vec<_> = stream::iter(jobs)
+            .map(|(name, lang)| async move {
+                let label = task_label(&name, lang.as_deref());
+                let result = job.render_task(self.data, name, lang.as_deref()).await;
+                (label, result)
+            })
+            .buffer_unordered(MAX_CONCURRENT_RENDERS)
+            .collect()
+            .await;
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((prepared, results))
+    }
+    /// Sends the PDFs with a summary of the batch. PDFs that Discord would
+    /// refuse are listed in the summary instead, and the rest is split across
+    /// as many messages as its size needs, zipped when there are more than
+    /// [`MAX_ATTACHMENTS`].
+    async fn reply_all(
+        &'a self,
+        commit: Oid,
+        results: TaskResults,
+    ) -> Result<(), TaskPdfWriterBotError> {
+        let total = results.len();
+        let mut rendered = 0;
+        let mut pdfs = Vec::new();
+        let mut summary = Vec::new();
+        for (name, result) in results {
+            let file = match result {
+                Ok(file) => file,
+                Err(e) => {
+                    summary.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            };
+            rendered += 1;
+            let size = fs::metadata(&file)?.len();
+            match check_upload_size(&file_name(&file), size) {
+                Ok(()) => pdfs.push((file, size)),
+                Err(e) => summary.push(format!("{}: {}", name, e)),
+            }
+        }
+        let zipped = pdfs.len() > MAX_ATTACHMENTS;
+        let parts = match zipped {
+            true => split_uploads(
+                pdfs.into_iter()
+                    .map(|(file, size)| (file, size + ZIP_ENTRY_OVERHEAD))
+                    .collect(),
+                usize::MAX,
+            ),
+            false => split_uploads(pdfs, MAX_ATTACHMENTS),
+        };
+        summary.insert(
+            0,
+            format!(
+                "Rendered {} of {} tasks at commit `{}`.",
+                rendered, total, commit
+            ),
+        );
+        if parts.len() > 1 {
+            summary.insert(
+                1,
+                format!(
+                    "The PDFs are too large for one message and follow in {} parts.",
+                    parts.len()
+                ),
+            );
+        }
+        let mut content = summary.join("\n");
+        // The summary goes with the first part, or alone when nothing is sent.
+        for (i, files) in parts.iter().enumerate() {
+            if i > 0 {
+                content = format!("Part {} of {}", i + 1, parts.len());
+            }
+            let mut attachments = Vec::new();
+            if zipped {
+                let filename = match parts.len() {
+                    1 => "tasks.zip".to_string(),
+                    _ => format!("tasks-{}.zip", i + 1),
+                };
+                let data = zip_pdfs(files)?;
+                match check_upload_size(&filename, data.len() as u64) {
+                    Ok(()) => attachments.push(AttachmentType::Bytes {
+                        data: Cow::Owned(data),
+                        filename,
+                    }),
+                    Err(e) => content += &format!("\n{}", e),
+                }
+            } else {
+                for file in files {
+                    attachments.push(AttachmentType::Bytes {
+                        data: Cow::Owned(fs::read(file)?),
+                        filename: file_name(file),
+                    });
+                }
+            }
+            self.data
+                .command
+                .create_followup_message(&self.data.ctx.http, |response| {
+                    response.content(&content).add_files(attachments)
+                })
+                .await?;
+        }
+        if parts.is_empty() {
+            self.data
+                .command
+                .create_followup_message(&self.data.ctx.http, |response| response.content(&content))
+                .await?;
+        }
+        Ok(())
     }
 }
 
@@ -99,6 +655,27 @@ impl<'a> CommandHandle<'a> for GenpdfHandler<'a> {
         command
             .name("genpdf")
             .description("Generates a PDF from markdown")
+            .create_option(|option| {
+                option
+                    .name("all")
+                    .description("Generate every task of the contest instead of this thread's task")
+                    .kind(CommandOptionType::Boolean)
+                    .required(false)
+            })
+            .create_option(|option| {
+                option
+                    .name("lang")
+                    .description("Language code of the statement, e.g. th for <task>.th.md, or all for every language")
+                    .kind(CommandOptionType::String)
+                    .required(false)
+            })
+            .create_option(|option| {
+                option
+                    .name("ref")
+                    .description("Branch, tag or commit to generate from (defaults to the configured branch)")
+                    .kind(CommandOptionType::String)
+                    .required(false)
+            })
     }
     async fn handle(&'a self) -> Result<(), TaskPdfWriterBotError> {
         self.data
@@ -107,15 +684,35 @@ impl<'a> CommandHandle<'a> for GenpdfHandler<'a> {
                 response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
             })
             .await?;
-        match self.run().await {
-            Ok(file) => {
+        let all_languages = self.data.string_option("lang") == Some(ALL_LANGUAGES);
+        if self.wants_all() || all_languages {
+            return match self.run_batch(self.wants_all()).await {
+                Ok((job, results)) => self.reply_all(job.commit, results).await,
+                Err(e) => {
+                    self.data
+                        .command
+                        .create_followup_message(&self.data.ctx.http, |response| {
+                            response.content(format!("{:?}", e))
+                        })
+                        .await?;
+                    Ok(())
+                }
+            };
+        }
+        let rendered = self.run().await.and_then(|(job, file)| {
+            check_upload_size(&file_name(&file), fs::metadata(&file)?.len())?;
+            Ok((job, file))
+        });
+        match rendered {
+            Ok((job, file)) => {
                 self.data
                     .command
                     .create_followup_message(&self.data.ctx.http, |response| {
-                        response.add_file(&file)
+                        response
+                            .content(format!("Rendered at commit `{}`.", job.commit))
+                            .add_file(&file)
                     })
                     .await?;
-                fs::remove_file(file.to_owned())?;
             }
             Err(e) => {
                 self.data
@@ -130,34 +727,46 @@ impl<'a> CommandHandle<'a> for GenpdfHandler<'a> {
     }
 }
 
-// The following stupid comment is for use in case of generating multiple files at the same time.
-
-// for entry in repo
-//     .path()
-//     .join("..")
-//     .join("contest")
-//     .read_dir()
-//     .expect("directory is read")
-// {
-//     if let Ok(ent) = entry {
-//         let file_name = ent.file_name().to_str().expect("to str").to_string();
-//         if !file_name.ends_with(".md") {
-//             continue;
-//         }
-//         let file_name = file_name
-//             .strip_suffix(".md")
-//             .expect(".md can be stripped")
-//             .to_string();
-//         let file_content = String::from_utf8_lossy(&fs::read(ent.path())?)
-//             .parse()
-//             .expect("file read success");
-//         file_futures.push(generate_pdf(file_name, file_content, config_json.clone()));
-//     }
-// }
-// let pdf_result: Vec<String> = join_all(file_futures)
-//     .await
-//     .iter()
-//     .map(|x| x.as_ref().expect("OK").clone())
-//     .collect();
-
-// return Ok(pdf_result);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_uploads_by_count_and_size() {
+        let mib = 1024 * 1024;
+        let sizes = [4 * mib, 4 * mib, 3 * mib, 11 * mib, mib, mib];
+        let items = sizes.iter().copied().enumerate().collect();
+        assert_eq!(
+            split_uploads(items, MAX_ATTACHMENTS),
+            [vec![0, 1], vec![2], vec![3], vec![4, 5]]
+        );
+        let items = (0..12).map(|i| (i, 1)).collect();
+        assert_eq!(
+            split_uploads(items, MAX_ATTACHMENTS),
+            [(0..10).collect::<Vec<_>>(), vec![10, 11]]
+        );
+        assert!(split_uploads(Vec::<(u8, u64)>::new(), MAX_ATTACHMENTS).is_empty());
+        assert!(check_upload_size("tasks.zip", MAX_UPLOAD_BYTES).is_ok());
+        let refused = check_upload_size("sum.pdf", 12 * mib).unwrap_err();
+        assert!(
+            refused.to_string().contains("sum.pdf is 12.0 MiB"),
+            "{}",
+            refused
+        );
+    }
+
+    #[test]
+    fn splits_only_language_suffixes() {
+        let configured = ["fil".to_string()];
+        assert_eq!(split_language("sum.th", &configured), ("sum", Some("th")));
+        assert_eq!(
+            split_language("sum.pt-BR", &configured),
+            ("sum", Some("pt-BR"))
+        );
+        assert_eq!(split_language("sum.fil", &configured), ("sum", Some("fil")));
+        assert_eq!(split_language("sum.fil", &[]), ("sum.fil", None));
+        assert_eq!(split_language("sum.old", &configured), ("sum.old", None));
+        assert_eq!(split_language("sum", &configured), ("sum", None));
+        assert_eq!(split_language(".th", &configured), (".th", None));
+    }
+}
@@ -1,4 +1,7 @@
+pub mod admin;
+pub mod booklet;
 pub mod config;
+pub mod contest;
 pub mod genpdf;
 pub mod ping;
 pub mod sendstr;
@@ -1,6 +1,5 @@
-use crate::traits::{
-    immediate_handle, CommandHandle, CommandHandlerData, MyError, TaskPdfWriterBotError,
-};
+use crate::contests;
+use crate::traits::{immediate_handle, CommandHandle, CommandHandlerData, TaskPdfWriterBotError};
 use crate::util::{get_metadata, get_name};
 
 use serenity::async_trait;
@@ -34,13 +33,18 @@ impl<'a> PingHandler<'a> {
                 .join_thread(&self.data.ctx.http)
                 .await?;
         }
-        let name = get_name(self.data.command.channel_id, &self.data.ctx).await;
-        let guild_id = match self.data.command.guild_id {
-            Some(s) => s,
-            None => Err(MyError::new("guild_id not found"))?,
-        };
-        let mdata = get_metadata(guild_id, self.data.database).await?;
-        Ok(name? + " | " + mdata.0.as_str() + " | " + mdata.1.as_str())
+        let name = get_name(self.data.command.channel_id, self.data.ctx).await;
+        let contest = contests::resolve(self.data).await?;
+        let mdata = get_metadata(&contest, self.data.store).await?;
+        Ok(name?
+            + " | "
+            + contest.name.as_str()
+            + " | "
+            + mdata.git_remote_url.as_str()
+            + " | "
+            + mdata.contest_rel_path.as_str()
+            + " | "
+            + self.data.renderer.identity().as_str())
     }
 }
 
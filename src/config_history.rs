@@ -0,0 +1,302 @@
+use sqlx::FromRow;
+
+use crate::contests::ContestRef;
+use crate::keyring::Keyring;
+use crate::store::{ConfigChange, ContestStore, RevokedSecret, SecretSlot};
+use crate::traits::TaskPdfWriterBotError;
+
+/// The columns of `contests` that make up a contest's configuration, copied
+/// as is to `contest_config_history`. Secrets stay encrypted.
+pub(crate) const COLUMNS: &[&str] = &[
+    "git_remote_url",
+    "contest_rel_path",
+    "private_key",
+    "private_key_version",
+    "https_username",
+    "https_token",
+    "https_token_version",
+    "renderer_url",
+    "default_branch",
+    "default_lang",
+];
+
+/// What replaces a revoked private key or token in the history: the sealed
+/// secret is gone, and `/config rollback` knows not to restore it.
+pub const REVOKED: &[u8] = &[];
+
+/// A contest's configuration at some point. Every field is `None` in the
+/// version recorded by `/config reset`.
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct ConfigVersion {
+    pub git_remote_url: Option<String>,
+    pub contest_rel_path: Option<String>,
+    pub private_key: Option<Vec<u8>>,
+    pub private_key_version: Option<i32>,
+    pub https_username: Option<String>,
+    pub https_token: Option<Vec<u8>>,
+    pub https_token_version: Option<i32>,
+    pub renderer_url: Option<String>,
+    pub default_branch: Option<String>,
+    pub default_lang: Option<String>,
+}
+
+impl ConfigVersion {
+    /// The settings that differ from `previous`, as shown by `/config history`.
+    /// Secrets are compared decrypted, since a master key rotation changes
+    /// their bytes but not the secret.
+    pub fn changes(
+        &self,
+        previous: &ConfigVersion,
+        keyring: &Keyring,
+        guild_id: &str,
+    ) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.git_remote_url != previous.git_remote_url {
+            changes.push("url");
+        }
+        if self.contest_rel_path != previous.contest_rel_path {
+            changes.push("reldir");
+        }
+        if !same_secret(
+            keyring,
+            guild_id,
+            (&self.private_key, self.private_key_version),
+            (&previous.private_key, previous.private_key_version),
+        ) {
+            changes.push("private key");
+        }
+        if self.https_username != previous.https_username
+            || !same_secret(
+                keyring,
+                guild_id,
+                (&self.https_token, self.https_token_version),
+                (&previous.https_token, previous.https_token_version),
+            )
+        {
+            changes.push("token");
+        }
+        if self.renderer_url != previous.renderer_url {
+            changes.push("renderer");
+        }
+        if self.default_branch != previous.default_branch {
+            changes.push("branch");
+        }
+        if self.default_lang != previous.default_lang {
+            changes.push("lang");
+        }
+        changes
+    }
+}
+
+impl ConfigVersion {
+    /// The secret in `slot`, with the master key version it is sealed with.
+    fn secret(&self, slot: SecretSlot) -> (&Option<Vec<u8>>, Option<i32>) {
+        match slot {
+            SecretSlot::ContestKey | SecretSlot::HistoryKey => {
+                (&self.private_key, self.private_key_version)
+            }
+            SecretSlot::ContestToken | SecretSlot::HistoryToken => {
+                (&self.https_token, self.https_token_version)
+            }
+        }
+    }
+}
+
+fn same_secret(
+    keyring: &Keyring,
+    guild_id: &str,
+    a: (&Option<Vec<u8>>, Option<i32>),
+    b: (&Option<Vec<u8>>, Option<i32>),
+) -> bool {
+    match (a.0, b.0) {
+        (None, None) => true,
+        (Some(x), Some(y)) if x == y => true,
+        (Some(x), Some(y)) => matches!(
+            (keyring.open(guild_id, x, a.1), keyring.open(guild_id, y, b.1)),
+            (Ok(x), Ok(y)) if x == y
+        ),
+        _ => false,
+    }
+}
+
+/// A row of `contest_config_history`: the configuration right after a change.
+#[derive(Clone, FromRow)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub changed_by_name: String,
+    /// Unix time.
+    pub changed_at: i64,
+    /// The `/config` subcommand that made the change.
+    pub action: String,
+    #[sqlx(flatten)]
+    pub version: ConfigVersion,
+}
+
+/// Applies `change` and, unless nothing changed since the last recorded
+/// version, records the new configuration in the history, in one
+/// transaction. Returns `false` if it deletes a contest that doesn't exist.
+///
+/// Revoked secrets are revoked by value: every copy in the history that
+/// decrypts to the same secret is revoked, even one sealed apart from it.
+pub async fn save(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+    mut change: ConfigChange<'_>,
+) -> Result<bool, TaskPdfWriterBotError> {
+    let guild_id = change.contest.guild_id.to_string();
+    let history = match change.revoked.is_empty() {
+        true => store.history(change.contest, 1).await?,
+        false => store.history(change.contest, i64::MAX).await?,
+    };
+    let mut copies = Vec::new();
+    for revoked in &change.revoked {
+        let sealed = Some(revoked.sealed.clone());
+        for entry in &history {
+            let (copy, version) = entry.version.secret(revoked.slot);
+            let is_copy = copy
+                .as_deref()
+                .is_some_and(|c| c != revoked.sealed && c != REVOKED)
+                && same_secret(
+                    keyring,
+                    &guild_id,
+                    (&sealed, revoked.version),
+                    (copy, version),
+                );
+            if is_copy {
+                copies.push(RevokedSecret {
+                    slot: revoked.slot,
+                    sealed: copy.clone().unwrap_or_default(),
+                    version,
+                });
+            }
+        }
+    }
+    change.revoked.extend(copies);
+    let previous = match history.into_iter().next() {
+        Some(last) => last.version,
+        None => ConfigVersion::default(),
+    };
+    let current = change.version.cloned().unwrap_or_default();
+    if current.changes(&previous, keyring, &guild_id).is_empty() {
+        change.author = None;
+    }
+    store.change_contest(&change).await
+}
+
+/// The `limit` latest versions of the contest's configuration, newest first,
+/// each with the settings it changed.
+pub async fn list(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+    contest: &ContestRef,
+    limit: i64,
+) -> Result<Vec<(HistoryEntry, Vec<&'static str>)>, TaskPdfWriterBotError> {
+    let guild_id = contest.guild_id.to_string();
+    // One more, to know what the oldest listed version changed.
+    let mut entries = store.history(contest, limit + 1).await?;
+    let older = match entries.len() as i64 > limit {
+        true => entries.pop().map(|e| e.version),
+        false => None,
+    };
+    let mut listed = Vec::new();
+    let mut previous = older.unwrap_or_default();
+    for entry in entries.into_iter().rev() {
+        let changes = entry.version.changes(&previous, keyring, &guild_id);
+        previous = entry.version.clone();
+        listed.push((entry, changes));
+    }
+    listed.reverse();
+    Ok(listed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use crate::store::ChangeAuthor;
+    use serenity::model::prelude::GuildId;
+
+    #[test]
+    fn compares_secrets_decrypted() {
+        let old = Keyring::new(vec![(1, vec![1; 32])], None).unwrap();
+        let rotated = Keyring::new(vec![(1, vec![1; 32]), (2, vec![2; 32])], None).unwrap();
+        let before = ConfigVersion {
+            git_remote_url: Some("https://github.com/owner/repo.git".to_string()),
+            private_key: Some(old.seal("42", b"private key").unwrap()),
+            private_key_version: Some(1),
+            ..Default::default()
+        };
+        let after = ConfigVersion {
+            private_key: Some(rotated.seal("42", b"private key").unwrap()),
+            private_key_version: Some(2),
+            ..before.clone()
+        };
+        assert!(after.changes(&before, &rotated, "42").is_empty());
+        let replaced = ConfigVersion {
+            private_key: Some(rotated.seal("42", b"another key").unwrap()),
+            default_branch: Some("main".to_string()),
+            ..after.clone()
+        };
+        assert_eq!(
+            replaced.changes(&after, &rotated, "42"),
+            vec!["private key", "branch"]
+        );
+        assert_eq!(
+            ConfigVersion::default().changes(&after, &rotated, "42"),
+            vec!["url", "private key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn records_changes_and_revokes_secrets() {
+        let store = MemoryStore::new();
+        let keyring = Keyring::new(vec![(1, vec![1; 32])], None).unwrap();
+        let contest = ContestRef {
+            guild_id: GuildId(42),
+            name: "default".to_string(),
+        };
+        let author = ChangeAuthor {
+            changed_by: "7",
+            changed_by_name: "admin",
+            action: "set",
+        };
+        let key = keyring.seal("42", b"private key").unwrap();
+        let version = ConfigVersion {
+            git_remote_url: Some("https://github.com/owner/repo.git".to_string()),
+            contest_rel_path: Some("contest".to_string()),
+            private_key: Some(key.clone()),
+            private_key_version: Some(1),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let change = ConfigChange {
+                author: Some(author),
+                ..ConfigChange::new(&contest, Some(&version))
+            };
+            assert!(save(&store, &keyring, change).await.unwrap());
+        }
+        // Saving the same configuration again records nothing.
+        assert_eq!(store.history(&contest, 5).await.unwrap().len(), 1);
+
+        let reset = ConfigChange {
+            author: Some(ChangeAuthor {
+                action: "reset",
+                ..author
+            }),
+            revoked: vec![RevokedSecret {
+                slot: SecretSlot::HistoryKey,
+                sealed: key,
+                version: Some(1),
+            }],
+            ..ConfigChange::new(&contest, None)
+        };
+        assert!(save(&store, &keyring, reset).await.unwrap());
+        assert!(store.contest(&contest).await.unwrap().is_none());
+        let history = store.history(&contest, 5).await.unwrap();
+        assert_eq!(history[0].action, "reset");
+        assert_eq!(history[1].version.private_key.as_deref(), Some(REVOKED));
+        assert!(store.secrets().await.unwrap().is_empty());
+        let again = ConfigChange::new(&contest, None);
+        assert!(!save(&store, &keyring, again).await.unwrap());
+    }
+}
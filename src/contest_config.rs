@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::traits::{MyError, TaskPdfWriterBotError};
+use crate::util::safe_join;
+
+/// A contest's `config.json`. It belongs to task-pdf-writer-v2: the bot only
+/// reads the fields below, none of them required, and sends every key to the
+/// renderer as is, including the ones it doesn't know.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContestConfig {
+    /// Short contest name, printed in the page header of every task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contest_name: Option<String>,
+    /// Full contest title, used on the booklet cover.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contest_full_title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contest_date: Option<String>,
+    /// Path of the contest logo, relative to the contest directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logo: Option<String>,
+    /// Only read by the bot, never sent to the renderer.
+    #[serde(default, skip_serializing)]
+    pub booklet: Option<BookletConfig>,
+    /// The keys the bot doesn't read, forwarded to the renderer.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The `booklet` section of `config.json`, read by `/booklet`. Every field
+/// falls back to its counterpart at the top level of the config.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BookletConfig {
+    pub title: Option<String>,
+    pub date: Option<String>,
+    /// A JPEG image relative to the contest directory.
+    pub logo: Option<String>,
+    /// Task order; defaults to every task, sorted by name.
+    pub tasks: Option<Vec<String>>,
+    /// Keys `/booklet` doesn't know, ignored.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// What gets sent to the renderer for one task.
+#[derive(Serialize)]
+pub struct RenderRequest<'a> {
+    #[serde(flatten)]
+    pub config: &'a ContestConfig,
+    pub task_name: &'a str,
+    pub content: &'a str,
+}
+
+impl ContestConfig {
+    /// Parses a config, naming `source` and the offending key on failure, e.g.
+    /// ``config.json: booklet.tasks[2]: invalid type: integer `3`, expected a string``.
+    pub fn parse(value: serde_json::Value, source: &str) -> Result<Self, TaskPdfWriterBotError> {
+        match serde_path_to_error::deserialize(value) {
+            Ok(config) => Ok(config),
+            Err(e) => Err(MyError::new(&format!(
+                "(probably your fault): {}: {}: {}",
+                source,
+                e.path(),
+                e.inner()
+            )))?,
+        }
+    }
+
+    /// The booklet section with the top-level fallbacks filled in.
+    pub fn booklet(&self) -> BookletConfig {
+        let booklet = self.booklet.clone().unwrap_or_default();
+        BookletConfig {
+            title: booklet
+                .title
+                .or_else(|| self.contest_full_title.clone())
+                .or_else(|| self.contest_name.clone()),
+            date: booklet.date.or_else(|| self.contest_date.clone()),
+            logo: booklet
+                .logo
+                .or_else(|| self.logo.clone().filter(|l| is_jpeg_name(l))),
+            tasks: booklet.tasks,
+            extra: booklet.extra,
+        }
+    }
+
+    /// Keys the bot doesn't know. They are no errors: task-pdf-writer-v2 may
+    /// read the top-level ones, but a typo in a known key ends up here too.
+    pub fn warnings(&self) -> Vec<String> {
+        let forwarded = self
+            .extra
+            .keys()
+            .map(|key| format!("{}: unknown key, sent to the renderer as is", key));
+        let ignored = self
+            .booklet
+            .iter()
+            .flat_map(|booklet| booklet.extra.keys())
+            .map(|key| format!("booklet.{}: unknown key, ignored", key));
+        forwarded.chain(ignored).collect()
+    }
+
+    /// Problems the types can't catch: missing files and unknown tasks. `tasks`
+    /// are the tasks found in the contest directory.
+    pub fn problems(&self, contest_dir: &Path, tasks: &[String]) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self
+            .contest_name
+            .as_ref()
+            .is_some_and(|n| n.trim().is_empty())
+        {
+            problems.push("contest_name: must not be empty".to_string());
+        }
+        if let Some(logo) = &self.logo {
+            if !is_contest_file(contest_dir, logo) {
+                problems.push(format!("logo: {} not found", logo));
+            }
+        }
+        let booklet = self.booklet();
+        if let Some(logo) = &booklet.logo {
+            if !is_jpeg_name(logo) {
+                problems.push(format!("booklet.logo: {} must be a JPEG image", logo));
+            } else if !is_contest_file(contest_dir, logo) {
+                problems.push(format!("booklet.logo: {} not found", logo));
+            }
+        }
+        if let Some(order) = &booklet.tasks {
+            let mut seen = HashSet::new();
+            for (i, task) in order.iter().enumerate() {
+                if !tasks.contains(task) {
+                    problems.push(format!("booklet.tasks[{}]: no task named {}", i, task));
+                }
+                if !seen.insert(task) {
+                    problems.push(format!("booklet.tasks[{}]: {} is listed twice", i, task));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Whether `path` is a file inside the contest directory, symlinks included.
+fn is_contest_file(contest_dir: &Path, path: &str) -> bool {
+    safe_join(contest_dir, path).is_ok_and(|p| p.is_file())
+}
+
+fn is_jpeg_name(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.ends_with(".jpg") || lower.ends_with(".jpeg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn forwards_unknown_keys_with_warnings() {
+        let config = ContestConfig::parse(
+            json!({
+                "contest_name": "MYCONTEST",
+                "font_size": 11,
+                "booklet": { "title": "My Contest", "colour": "red" }
+            }),
+            "config.json",
+        )
+        .unwrap();
+        assert_eq!(
+            config.warnings(),
+            vec![
+                "font_size: unknown key, sent to the renderer as is",
+                "booklet.colour: unknown key, ignored"
+            ]
+        );
+        let request = serde_json::to_value(RenderRequest {
+            config: &config,
+            task_name: "sum",
+            content: "# Sum",
+        })
+        .unwrap();
+        assert_eq!(request["font_size"], 11);
+        assert_eq!(request["contest_name"], "MYCONTEST");
+        assert!(request.get("booklet").is_none());
+
+        let empty = ContestConfig::parse(json!({}), "config.json").unwrap();
+        assert!(empty.warnings().is_empty());
+        assert_eq!(empty.booklet().title, None);
+        let wrong = ContestConfig::parse(json!({ "logo": 3 }), "config.json");
+        assert!(wrong.unwrap_err().to_string().contains("logo"));
+    }
+}
@@ -0,0 +1,290 @@
+use serenity::model::prelude::{Channel, ChannelId, GuildId};
+use serenity::prelude::Context;
+
+use crate::config_history::ConfigVersion;
+use crate::store::{ChangeAuthor, ConfigChange, ContestStore};
+use crate::traits::{CommandHandlerData, MyError, TaskPdfWriterBotError};
+
+/// The contest of a guild that never created one with `/contest create`.
+pub const DEFAULT_CONTEST: &str = "default";
+
+/// One of the named contests of a guild.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContestRef {
+    pub guild_id: GuildId,
+    pub name: String,
+}
+
+/// A contest as listed by `/contest list`.
+#[derive(sqlx::FromRow)]
+pub struct ContestSummary {
+    pub name: String,
+    /// The channel or category the contest is bound to.
+    pub channel_id: Option<String>,
+    pub git_remote_url: String,
+    pub contest_rel_path: String,
+}
+
+/// Contest names are short and plain, since they are typed in commands.
+pub fn check_name(name: &str) -> Result<(), TaskPdfWriterBotError> {
+    let valid = (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        Err(MyError::new(
+            "(probably your fault): a contest name is 1 to 32 lowercase letters, digits, - or _",
+        ))?;
+    }
+    Ok(())
+}
+
+/// The channel a command was run in, then its parent channel (for a thread)
+/// and category, nearest first.
+async fn channel_chain(
+    ctx: &Context,
+    channel_id: ChannelId,
+) -> Result<Vec<ChannelId>, TaskPdfWriterBotError> {
+    let mut chain = vec![channel_id];
+    // A thread, its channel and the channel's category.
+    while chain.len() < 3 {
+        let parent = match chain[chain.len() - 1].to_channel(ctx).await? {
+            Channel::Guild(channel) => channel.parent_id,
+            _ => None,
+        };
+        match parent {
+            Some(p) => chain.push(p),
+            None => break,
+        }
+    }
+    Ok(chain)
+}
+
+/// Picks the contest bound to the nearest channel of `chain`.
+fn nearest_binding(chain: &[ChannelId], bindings: &[(String, String)]) -> Option<String> {
+    chain.iter().find_map(|channel_id| {
+        bindings
+            .iter()
+            .find(|(_, bound)| *bound == channel_id.to_string())
+            .map(|(name, _)| name.clone())
+    })
+}
+
+/// The contest a command applies to: the one bound to the channel it was run
+/// in (or to that channel's parent channel or category), else the guild's
+/// active contest, else [`DEFAULT_CONTEST`].
+pub async fn resolve(data: &CommandHandlerData<'_>) -> Result<ContestRef, TaskPdfWriterBotError> {
+    let guild_id = match data.command.guild_id {
+        Some(g) => g,
+        None => Err(MyError::new("guild_id not found"))?,
+    };
+    let bindings: Vec<(String, String)> = data
+        .store
+        .contests(guild_id)
+        .await?
+        .into_iter()
+        .filter_map(|c| Some((c.name, c.channel_id?)))
+        .collect();
+    let bound = match bindings.is_empty() {
+        true => None,
+        false => nearest_binding(
+            &channel_chain(data.ctx, data.command.channel_id).await?,
+            &bindings,
+        ),
+    };
+    let name = match bound {
+        Some(name) => name,
+        None => data
+            .store
+            .active_contest(guild_id)
+            .await?
+            .unwrap_or_else(|| DEFAULT_CONTEST.to_string()),
+    };
+    Ok(ContestRef { guild_id, name })
+}
+
+/// Refuses to bind a channel that another contest of the guild is bound to.
+async fn check_unbound(
+    store: &dyn ContestStore,
+    contest: &ContestRef,
+    channel_id: ChannelId,
+) -> Result<(), TaskPdfWriterBotError> {
+    let channel = channel_id.to_string();
+    let other = store
+        .contests(contest.guild_id)
+        .await?
+        .into_iter()
+        .find(|c| c.name != contest.name && c.channel_id.as_ref() == Some(&channel));
+    if let Some(other) = other {
+        Err(MyError::new(&format!(
+            "(probably your fault): <#{}> is already bound to the contest {}",
+            channel_id, other.name
+        )))?;
+    }
+    Ok(())
+}
+
+/// Creates the contest `name` with the settings of `from`, secrets included,
+/// so only what differs has to be changed afterwards. The copy and the
+/// binding are saved at once and recorded in the history of the new contest.
+pub async fn create(
+    store: &dyn ContestStore,
+    from: &ContestRef,
+    name: &str,
+    channel_id: Option<ChannelId>,
+    author: ChangeAuthor<'_>,
+) -> Result<ContestRef, TaskPdfWriterBotError> {
+    check_name(name)?;
+    let created = ContestRef {
+        guild_id: from.guild_id,
+        name: name.to_string(),
+    };
+    let settings = match store.contest(from).await? {
+        Some(s) => s,
+        None => Err(MyError::new(&format!(
+            "(probably your fault): set the contest {} up with `/config set` first, the new contest starts as a copy of it",
+            from.name
+        )))?,
+    };
+    let change = ConfigChange {
+        author: Some(author),
+        create: true,
+        channel_id,
+        ..ConfigChange::new(&created, Some(&settings))
+    };
+    if !store.change_contest(&change).await? {
+        // Tell which of the two got in the way.
+        if let Some(c) = channel_id {
+            check_unbound(store, &created, c).await?;
+        }
+        Err(MyError::new(&format!(
+            "(probably your fault): the contest {} already exists",
+            name
+        )))?;
+    }
+    Ok(created)
+}
+
+/// Binds the contest to a channel or category, or unbinds it with `None`.
+/// Returns whether the contest exists.
+pub async fn bind(
+    store: &dyn ContestStore,
+    contest: &ContestRef,
+    channel_id: Option<ChannelId>,
+) -> Result<bool, TaskPdfWriterBotError> {
+    if let Some(c) = channel_id {
+        check_unbound(store, contest, c).await?;
+    }
+    store.bind_contest(contest, channel_id).await
+}
+
+/// The configuration of a contest that was set up with `/config set`.
+pub async fn configured(
+    store: &dyn ContestStore,
+    contest: &ContestRef,
+) -> Result<ConfigVersion, TaskPdfWriterBotError> {
+    match store.contest(contest).await? {
+        Some(s) => Ok(s),
+        None => Err(MyError::new(
+            "(probably your fault): set the repository up with `/config set` first",
+        ))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn checks_contest_names() {
+        assert!(check_name("qualifier-2023").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("Final").is_err());
+        assert!(check_name("a b").is_err());
+    }
+
+    #[test]
+    fn prefers_the_nearest_binding() {
+        let bindings = vec![
+            ("final".to_string(), "30".to_string()),
+            ("mirror".to_string(), "20".to_string()),
+        ];
+        // A thread (10) in a channel (20) of a category (30).
+        let chain = [ChannelId(10), ChannelId(20), ChannelId(30)];
+        assert_eq!(
+            nearest_binding(&chain, &bindings).as_deref(),
+            Some("mirror")
+        );
+        assert_eq!(
+            nearest_binding(&[ChannelId(11), ChannelId(30)], &bindings).as_deref(),
+            Some("final")
+        );
+        assert_eq!(nearest_binding(&[ChannelId(12)], &bindings), None);
+    }
+
+    #[tokio::test]
+    async fn creates_contests_as_copies() {
+        let store = MemoryStore::new();
+        let default = ContestRef {
+            guild_id: GuildId(1),
+            name: DEFAULT_CONTEST.to_string(),
+        };
+        assert!(configured(&store, &default).await.is_err());
+        let settings = ConfigVersion {
+            git_remote_url: Some("https://github.com/owner/repo.git".to_string()),
+            contest_rel_path: Some("contest".to_string()),
+            https_token: Some(b"sealed".to_vec()),
+            https_token_version: Some(1),
+            ..Default::default()
+        };
+        store.save_contest(&default, &settings).await.unwrap();
+        let author = ChangeAuthor {
+            changed_by: "7",
+            changed_by_name: "admin",
+            action: "contest create",
+        };
+        let final_round = create(&store, &default, "final", Some(ChannelId(20)), author)
+            .await
+            .unwrap();
+        let history = store.history(&final_round, 5).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].action, "contest create");
+        assert_eq!(history[0].version.https_token, settings.https_token);
+        let summaries = store.contests(default.guild_id).await.unwrap();
+        let summary = summaries.iter().find(|c| c.name == "final").unwrap();
+        assert_eq!(summary.channel_id.as_deref(), Some("20"));
+        let mut changed = configured(&store, &final_round).await.unwrap();
+        changed.contest_rel_path = Some("final".to_string());
+        store.save_contest(&final_round, &changed).await.unwrap();
+        let copied = store.contest(&final_round).await.unwrap().unwrap();
+        assert_eq!(copied.https_token.as_deref(), Some(&b"sealed"[..]));
+        assert_eq!(copied.contest_rel_path.as_deref(), Some("final"));
+        assert_eq!(
+            store
+                .contest(&default)
+                .await
+                .unwrap()
+                .unwrap()
+                .contest_rel_path,
+            settings.contest_rel_path
+        );
+        // Names and channels are taken once, and a refused contest leaves
+        // nothing behind.
+        assert!(create(&store, &default, "final", None, author)
+            .await
+            .is_err());
+        assert!(
+            create(&store, &default, "mirror", Some(ChannelId(20)), author)
+                .await
+                .is_err()
+        );
+        let mirror = ContestRef {
+            guild_id: default.guild_id,
+            name: "mirror".to_string(),
+        };
+        assert!(store.contest(&mirror).await.unwrap().is_none());
+        assert!(store.history(&mirror, 5).await.unwrap().is_empty());
+        assert_eq!(store.history(&final_round, 5).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,428 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ipnet::IpNet;
+use shuttle_secrets::SecretStore;
+use url::{Host, Url};
+
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+/// The parts of a git remote URL the bot cares about. Both URLs with a scheme
+/// (`https://host/repo.git`, `ssh://git@host:2222/repo.git`) and scp-style
+/// ones (`git@host:owner/repo.git`) are understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitRemote {
+    /// Lowercase, with `git+ssh` and `ssh+git` folded into `ssh`; `file` for
+    /// local paths.
+    pub scheme: String,
+    pub user: Option<String>,
+    /// Without the brackets of IPv6 literals; empty for local paths.
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl GitRemote {
+    pub fn parse(url: &str) -> Result<Self, TaskPdfWriterBotError> {
+        let url = url.trim();
+        if url.contains("://") {
+            return Self::parse_with_scheme(url);
+        }
+        // Like git itself: `[user@]host:path` is scp-style unless a slash
+        // comes before the first colon, in which case it is a local path.
+        let (user, rest) = match url.split_once('@') {
+            Some((user, rest)) if !user.contains(['/', ':']) => (Some(user.to_string()), rest),
+            _ => (None, url),
+        };
+        let host = match rest.strip_prefix('[') {
+            Some(bracketed) => bracketed.split_once("]:").map(|(host, _path)| host),
+            None => rest
+                .split_once(':')
+                .map(|(host, _path)| host)
+                .filter(|host| !host.contains('/')),
+        };
+        let host = match host {
+            Some(h) if !h.is_empty() => h,
+            Some(_) => Err(MyError::new(&format!(
+                "(probably your fault): no host in the URL {}",
+                url
+            )))?,
+            None => {
+                return Ok(GitRemote {
+                    scheme: "file".to_string(),
+                    user: None,
+                    host: String::new(),
+                    port: None,
+                })
+            }
+        };
+        Ok(GitRemote {
+            scheme: "ssh".to_string(),
+            user,
+            host: host.to_lowercase(),
+            port: None,
+        })
+    }
+
+    fn parse_with_scheme(url: &str) -> Result<Self, TaskPdfWriterBotError> {
+        let parsed = match Url::parse(url) {
+            Ok(p) => p,
+            Err(e) => Err(MyError::new(&format!(
+                "(probably your fault): invalid URL {}: {}",
+                url, e
+            )))?,
+        };
+        let scheme = match parsed.scheme() {
+            "git+ssh" | "ssh+git" => "ssh".to_string(),
+            s => s.to_string(),
+        };
+        let host = match parsed.host() {
+            Some(Host::Domain(d)) => d.to_lowercase(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => String::new(),
+        };
+        Ok(GitRemote {
+            scheme,
+            user: Some(parsed.username().to_string()).filter(|u| !u.is_empty()),
+            host,
+            port: parsed.port(),
+        })
+    }
+
+    /// The port to connect to, the scheme's default if the URL has none.
+    pub fn port_or_default(&self) -> u16 {
+        match self.port {
+            Some(p) => p,
+            None => match self.scheme.as_str() {
+                "ssh" => 22,
+                "http" => 80,
+                "git" => 9418,
+                _ => 443,
+            },
+        }
+    }
+}
+
+pub const DEFAULT_ALLOWED_SCHEMES: &str = "https,ssh";
+
+/// Which git remotes guilds may point the bot at. Checked when `/config set`
+/// stores a URL and again before every fetch, since DNS may have changed.
+///
+/// The renderer is requested at the addresses that were checked, but libgit2
+/// resolves the host of a git remote again when it connects. A host whose DNS
+/// answers change between the check and the fetch (DNS rebinding) can still
+/// reach a private address that way; deployments where that matters should
+/// also restrict `GIT_ALLOWED_HOSTS` or block private ranges at the network
+/// level.
+pub struct RemotePolicy {
+    schemes: Vec<String>,
+    /// Empty means every host that isn't denied.
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    allow_private: bool,
+    /// Hosts guilds may point `/config set renderer` at. Empty means every
+    /// host that isn't denied.
+    renderer_hosts: Vec<String>,
+}
+
+fn secret_list(secret_store: &SecretStore, key: &str, default: &str) -> Vec<String> {
+    secret_store
+        .get(key)
+        .unwrap_or_else(|| default.to_string())
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// `pattern` is either a host name or `*.domain`, matching every subdomain.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Loopback, private, link-local, shared, multicast and reserved ranges, which
+/// must not be reachable through a guild's remote URL. Local-use NAT64 and
+/// Teredo are refused outright, since the IPv4 address behind them can't be
+/// checked.
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b:1::/48",
+    "2001::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// The IPv4 address embedded in an IPv4-mapped (`::ffff:0:0/96`), NAT64
+/// (`64:ff9b::/96`) or 6to4 (`2002::/16`) address, which is where traffic to
+/// it ends up.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [a, b, c, d, e, f, g, h] = ip.segments();
+    let from_segments =
+        |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match (a, b, c, d, e, f) {
+        (0x64, 0xff9b, 0, 0, 0, 0) => Some(from_segments(g, h)),
+        (0x2002, ..) => Some(from_segments(b, c)),
+        _ => ip.to_ipv4_mapped(),
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => match embedded_ipv4(v6) {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    };
+    PRIVATE_RANGES
+        .iter()
+        .any(|range| range.parse::<IpNet>().unwrap().contains(&ip))
+}
+
+impl RemotePolicy {
+    /// Reads `GIT_ALLOWED_SCHEMES`, `GIT_ALLOWED_HOSTS`, `GIT_DENIED_HOSTS`,
+    /// `RENDERER_ALLOWED_HOSTS` (comma-separated) and `GIT_ALLOW_PRIVATE_HOSTS`
+    /// (`true` or `false`).
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        RemotePolicy {
+            schemes: secret_list(secret_store, "GIT_ALLOWED_SCHEMES", DEFAULT_ALLOWED_SCHEMES),
+            allowed_hosts: secret_list(secret_store, "GIT_ALLOWED_HOSTS", ""),
+            denied_hosts: secret_list(secret_store, "GIT_DENIED_HOSTS", ""),
+            allow_private: secret_store.get("GIT_ALLOW_PRIVATE_HOSTS").as_deref() == Some("true"),
+            renderer_hosts: secret_list(secret_store, "RENDERER_ALLOWED_HOSTS", ""),
+        }
+    }
+
+    /// Parses `url` and refuses it if its scheme or host isn't allowed, or if
+    /// the host resolves to a private address.
+    pub async fn check(&self, url: &str) -> Result<GitRemote, TaskPdfWriterBotError> {
+        let remote = GitRemote::parse(url)?;
+        if !self.schemes.contains(&remote.scheme) {
+            Err(MyError::new(&format!(
+                "(probably your fault): {} URLs are not allowed, use one of: {}",
+                remote.scheme,
+                self.schemes.join(", ")
+            )))?;
+        }
+        self.check_host(&remote, &self.allowed_hosts)?;
+        self.public_addresses(&remote).await?;
+        Ok(remote)
+    }
+
+    /// Checks the renderer endpoint of a guild, which the bot POSTs to: only
+    /// `https`, to a host allowed by `RENDERER_ALLOWED_HOSTS` that doesn't
+    /// resolve to a private address. Returns the host and the addresses it
+    /// resolved to, so the request goes to the addresses that were checked.
+    pub async fn check_renderer(
+        &self,
+        url: &str,
+    ) -> Result<(String, Vec<SocketAddr>), TaskPdfWriterBotError> {
+        let remote = GitRemote::parse(url)?;
+        if remote.scheme != "https" {
+            Err(MyError::new(
+                "(probably your fault): the renderer URL must be an https URL",
+            ))?;
+        }
+        self.check_host(&remote, &self.renderer_hosts)?;
+        let addresses = self.public_addresses(&remote).await?;
+        Ok((remote.host, addresses))
+    }
+
+    /// Refuses a denied host, or one missing from `allowed` unless it is empty.
+    fn check_host(
+        &self,
+        remote: &GitRemote,
+        allowed: &[String],
+    ) -> Result<(), TaskPdfWriterBotError> {
+        if self
+            .denied_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &remote.host))
+            || (!allowed.is_empty()
+                && !allowed
+                    .iter()
+                    .any(|pattern| host_matches(pattern, &remote.host)))
+        {
+            Err(MyError::new(&format!(
+                "(probably your fault): the host {} is not allowed",
+                remote.host
+            )))?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the host and refuses it if any of its addresses is private.
+    /// Returns nothing to check when private hosts are allowed.
+    async fn public_addresses(
+        &self,
+        remote: &GitRemote,
+    ) -> Result<Vec<SocketAddr>, TaskPdfWriterBotError> {
+        if self.allow_private {
+            return Ok(Vec::new());
+        }
+        let addresses: Vec<_> =
+            match tokio::net::lookup_host((remote.host.as_str(), remote.port_or_default())).await {
+                Ok(addresses) => addresses.collect(),
+                Err(e) => Err(MyError::new(&format!(
+                    "(probably your fault): cannot resolve {}: {}",
+                    remote.host, e
+                )))?,
+            };
+        if addresses.is_empty() || addresses.iter().any(|a| is_private(a.ip())) {
+            Err(MyError::new(&format!(
+                "(probably your fault): the host {} resolves to a private address",
+                remote.host
+            )))?;
+        }
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+impl RemotePolicy {
+    /// The default policy, denying `*.internal.example` as well.
+    pub fn for_tests() -> Self {
+        RemotePolicy {
+            schemes: vec!["https".to_string(), "ssh".to_string()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: vec!["*.internal.example".to_string()],
+            allow_private: false,
+            renderer_hosts: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_remote_urls() {
+        let scp = GitRemote::parse("git@gitlab.com:owner/repo.git").unwrap();
+        assert_eq!(
+            (
+                scp.scheme.as_str(),
+                scp.user.as_deref(),
+                scp.host.as_str(),
+                scp.port_or_default()
+            ),
+            ("ssh", Some("git"), "gitlab.com", 22)
+        );
+        let ssh = GitRemote::parse("ssh://git@Gitea.Example.org:2222/owner/repo.git").unwrap();
+        assert_eq!(
+            (ssh.host.as_str(), ssh.port),
+            ("gitea.example.org", Some(2222))
+        );
+        let https = GitRemote::parse("https://github.com/owner/repo.git").unwrap();
+        assert_eq!((https.scheme.as_str(), https.user), ("https", None));
+        let v6 = GitRemote::parse("https://[::1]/repo.git").unwrap();
+        assert_eq!(v6.host, "::1");
+        let scp_v6 = GitRemote::parse("git@[fe80::1]:repo.git").unwrap();
+        assert_eq!(
+            (scp_v6.scheme.as_str(), scp_v6.host.as_str()),
+            ("ssh", "fe80::1")
+        );
+        assert_eq!(GitRemote::parse("/srv/repo.git").unwrap().scheme, "file");
+        assert_eq!(GitRemote::parse("./a:b").unwrap().scheme, "file");
+    }
+
+    #[test]
+    fn classifies_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "::1",
+            "fd00::1",
+            "::ffff:192.168.0.1",
+            "64:ff9b::a00:1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b:1::8c52:7003",
+            "2002:c0a8:1::1",
+            "2002:a9fe:a9fe::",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "140.82.112.3",
+            "2606:4700::1111",
+            "64:ff9b::8c52:7003",
+            "2002:8c52:7003::1",
+        ] {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        assert!(host_matches("*.example.com", "git.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(host_matches("github.com", "github.com"));
+    }
+
+    #[tokio::test]
+    async fn refuses_disallowed_remotes() {
+        let policy = RemotePolicy::for_tests();
+        assert!(policy.check("file:///etc").await.is_err());
+        assert!(policy.check("/srv/repo.git").await.is_err());
+        assert!(policy.check("git://93.184.216.34/repo.git").await.is_err());
+        assert!(policy.check("https://127.0.0.1/repo.git").await.is_err());
+        assert!(policy.check("git@[::1]:repo.git").await.is_err());
+        assert!(policy
+            .check("https://10.0.0.8:8443/repo.git")
+            .await
+            .is_err());
+        assert!(policy
+            .check("https://git.internal.example/repo.git")
+            .await
+            .is_err());
+        assert!(policy.check("https://93.184.216.34/repo.git").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_disallowed_renderers() {
+        let mut policy = RemotePolicy::for_tests();
+        assert!(policy
+            .check_renderer("http://93.184.216.34/genpdf")
+            .await
+            .is_err());
+        assert!(policy
+            .check_renderer("https://127.0.0.1/genpdf")
+            .await
+            .is_err());
+        assert!(policy
+            .check_renderer("https://169.254.169.254/latest/meta-data")
+            .await
+            .is_err());
+        let (host, addresses) = policy
+            .check_renderer("https://93.184.216.34/genpdf")
+            .await
+            .unwrap();
+        assert_eq!(host, "93.184.216.34");
+        assert_eq!(addresses[0].ip().to_string(), "93.184.216.34");
+        policy.renderer_hosts = vec!["renderer.example.com".to_string()];
+        assert!(policy
+            .check_renderer("https://93.184.216.34/genpdf")
+            .await
+            .is_err());
+    }
+}
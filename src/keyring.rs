@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use shuttle_secrets::SecretStore;
+use tracing::{info, warn};
+
+use crate::store::{ContestStore, SecretSlot, StoredSecret};
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+const NONCE_LEN: usize = 12;
+
+/// The master keys that encrypt the stored private keys and tokens, by
+/// version. New secrets are always sealed with the current
+/// version; older keys are kept so secrets sealed before a rotation can still
+/// be opened.
+pub struct Keyring {
+    current: i32,
+    keys: BTreeMap<i32, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    /// Reads `MASTER_KEYS`, a comma-separated list of `<version>:<base64 of
+    /// 32 bytes>`, and `MASTER_KEY_VERSION`, the version to seal with
+    /// (defaults to the highest one).
+    pub fn from_secrets(secret_store: &SecretStore) -> Result<Self, TaskPdfWriterBotError> {
+        let list = match secret_store.get("MASTER_KEYS") {
+            Some(l) => l,
+            None => Err(MyError::new("'MASTER_KEYS' was not found"))?,
+        };
+        let mut keys = Vec::new();
+        for entry in list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let parsed = entry.split_once(':').and_then(|(version, key)| {
+                let key = general_purpose::STANDARD.decode(key).ok()?;
+                Some((version.parse().ok()?, key))
+            });
+            match parsed {
+                Some((version, key)) if key.len() == 32 => keys.push((version, key)),
+                _ => Err(MyError::new(
+                    "'MASTER_KEYS' must look like 1:<base64 of 32 bytes>,2:<...>",
+                ))?,
+            }
+        }
+        let current = match secret_store.get("MASTER_KEY_VERSION") {
+            Some(v) => match v.parse() {
+                Ok(v) => Some(v),
+                Err(_) => Err(MyError::new("'MASTER_KEY_VERSION' is not a number"))?,
+            },
+            None => None,
+        };
+        Keyring::new(keys, current)
+    }
+
+    pub fn new(
+        keys: Vec<(i32, Vec<u8>)>,
+        current: Option<i32>,
+    ) -> Result<Self, TaskPdfWriterBotError> {
+        let keys: BTreeMap<i32, ChaCha20Poly1305> = keys
+            .into_iter()
+            .map(|(version, key)| (version, ChaCha20Poly1305::new(Key::from_slice(&key))))
+            .collect();
+        let current = match current.or_else(|| keys.keys().next_back().copied()) {
+            Some(v) if keys.contains_key(&v) => v,
+            Some(v) => Err(MyError::new(&format!("no master key with version {}", v)))?,
+            None => Err(MyError::new("no master key configured"))?,
+        };
+        Ok(Keyring { current, keys })
+    }
+
+    pub fn current_version(&self) -> i32 {
+        self.current
+    }
+
+    /// Encrypts the private key of a guild with the current master key. The
+    /// guild ID is authenticated too, so a key can't be moved to another guild.
+    /// The contest is left out on purpose: `/contest create` copies the secrets
+    /// of a contest, and the same members configure every contest of a guild,
+    /// so moving a key between them gains nothing.
+    /// Returns the nonce followed by the ciphertext.
+    pub fn seal(&self, guild_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: guild_id.as_bytes(),
+        };
+        match self.keys[&self.current].encrypt(&nonce, payload) {
+            Ok(ciphertext) => Ok([nonce.as_slice(), &ciphertext].concat()),
+            Err(_) => Err(MyError::new("cannot encrypt the private key"))?,
+        }
+    }
+
+    /// Decrypts a private key sealed with master key `version`; `None` is a
+    /// key stored before encryption at rest, which is returned as is.
+    pub fn open(
+        &self,
+        guild_id: &str,
+        sealed: &[u8],
+        version: Option<i32>,
+    ) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+        let version = match version {
+            Some(v) => v,
+            None => {
+                warn!("the private key of {} is not encrypted", guild_id);
+                return Ok(sealed.to_vec());
+            }
+        };
+        let cipher = match self.keys.get(&version) {
+            Some(c) => c,
+            None => Err(MyError::new(&format!(
+                "the private key is encrypted with master key version {}, which is not configured",
+                version
+            )))?,
+        };
+        if sealed.len() < NONCE_LEN {
+            Err(MyError::new("the stored private key is truncated"))?;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: guild_id.as_bytes(),
+        };
+        match cipher.decrypt(Nonce::from_slice(nonce), payload) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(MyError::new(
+                "cannot decrypt the stored private key, it was tampered with or the master key is wrong",
+            ))?,
+        }
+    }
+}
+
+/// What [`rotate_keys`] did.
+#[derive(Debug, Default)]
+pub struct Resealed {
+    /// How many stored copies of secrets were re-encrypted.
+    pub count: u64,
+    /// The secrets that could not be, each with why.
+    pub failures: Vec<String>,
+}
+
+/// Re-encrypts with the current master key every stored secret that is not
+/// encrypted with it, or only the unencrypted ones. Each secret is sealed once
+/// for all its copies in the contests and their history, so copies keep
+/// sharing their ciphertext. A secret that fails is left as is and reported,
+/// the others are re-encrypted anyway.
+async fn reseal(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+    only_plaintext: bool,
+) -> Result<Resealed, TaskPdfWriterBotError> {
+    let current = keyring.current_version();
+    let mut resealed = Resealed::default();
+    let mut done = Vec::new();
+    for secret in store.secrets().await? {
+        let stale = match secret.version {
+            None => true,
+            Some(v) => !only_plaintext && v != current,
+        };
+        let copies = (
+            secret.slot.copies(),
+            secret.guild_id.clone(),
+            secret.sealed.clone(),
+        );
+        if !stale || done.contains(&copies) {
+            continue;
+        }
+        done.push(copies);
+        match reseal_one(store, keyring, &secret).await {
+            Ok(count) => resealed.count += count,
+            Err(e) => resealed.failures.push(format!(
+                "the {} of guild {}: {}",
+                describe(secret.slot),
+                secret.guild_id,
+                e
+            )),
+        }
+    }
+    Ok(resealed)
+}
+
+async fn reseal_one(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+    secret: &StoredSecret,
+) -> Result<u64, TaskPdfWriterBotError> {
+    let plaintext = keyring.open(&secret.guild_id, &secret.sealed, secret.version)?;
+    let sealed = keyring.seal(&secret.guild_id, &plaintext)?;
+    // Only replace the secret that was read, a concurrent `/config` wins.
+    store
+        .replace_secret(secret, &sealed, keyring.current_version())
+        .await
+}
+
+fn describe(slot: SecretSlot) -> &'static str {
+    match slot {
+        SecretSlot::ContestKey => "private key",
+        SecretSlot::ContestToken => "token",
+        SecretSlot::HistoryKey => "private key in the history",
+        SecretSlot::HistoryToken => "token in the history",
+    }
+}
+
+/// Encrypts the private keys stored before encryption at rest. Run at startup.
+pub async fn encrypt_plaintext_keys(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+) -> Result<(), TaskPdfWriterBotError> {
+    let resealed = reseal(store, keyring, true).await?;
+    if resealed.count > 0 {
+        info!("encrypted {} stored private key(s)", resealed.count);
+    }
+    for failure in &resealed.failures {
+        warn!("cannot encrypt {}", failure);
+    }
+    Ok(())
+}
+
+/// Re-encrypts every stored private key and token with the current master key, so the
+/// older master keys can be removed from `MASTER_KEYS`.
+pub async fn rotate_keys(
+    store: &dyn ContestStore,
+    keyring: &Keyring,
+) -> Result<Resealed, TaskPdfWriterBotError> {
+    reseal(store, keyring, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_history::ConfigVersion;
+    use crate::contests::ContestRef;
+    use crate::store::memory::MemoryStore;
+    use crate::store::{ChangeAuthor, ConfigChange};
+    use serenity::model::prelude::GuildId;
+
+    fn keyring(current: i32) -> Keyring {
+        Keyring::new(vec![(1, vec![1; 32]), (2, vec![2; 32])], Some(current)).unwrap()
+    }
+
+    #[test]
+    fn seals_and_opens() {
+        let keyring = keyring(2);
+        let sealed = keyring.seal("42", b"private key").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..], b"private key");
+        assert_eq!(
+            keyring.open("42", &sealed, Some(2)).unwrap(),
+            b"private key"
+        );
+        // Another guild, another version or a flipped bit all fail.
+        assert!(keyring.open("43", &sealed, Some(2)).is_err());
+        assert!(keyring.open("42", &sealed, Some(1)).is_err());
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(keyring.open("42", &tampered, Some(2)).is_err());
+    }
+
+    #[test]
+    fn opens_keys_sealed_before_a_rotation() {
+        let sealed = keyring(1).seal("42", b"private key").unwrap();
+        let rotated = keyring(2);
+        assert_eq!(
+            rotated.open("42", &sealed, Some(1)).unwrap(),
+            b"private key"
+        );
+        assert_eq!(rotated.open("42", b"plain", None).unwrap(), b"plain");
+        assert!(Keyring::new(vec![(1, vec![1; 32])], Some(3)).is_err());
+        assert_eq!(
+            Keyring::new(vec![(1, vec![1; 32]), (7, vec![7; 32])], None)
+                .unwrap()
+                .current_version(),
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn rotates_contests_and_their_history() {
+        let store = MemoryStore::new();
+        let contest = ContestRef {
+            guild_id: GuildId(42),
+            name: "default".to_string(),
+        };
+        let version = ConfigVersion {
+            git_remote_url: Some("https://github.com/owner/repo.git".to_string()),
+            contest_rel_path: Some("contest".to_string()),
+            private_key: Some(b"private key".to_vec()),
+            https_token: Some(keyring(1).seal("42", b"token").unwrap()),
+            https_token_version: Some(1),
+            ..Default::default()
+        };
+        let author = ChangeAuthor {
+            changed_by: "7",
+            changed_by_name: "admin",
+            action: "set",
+        };
+        store
+            .change_contest(&ConfigChange {
+                author: Some(author),
+                ..ConfigChange::new(&contest, Some(&version))
+            })
+            .await
+            .unwrap();
+        let keyring = keyring(2);
+        encrypt_plaintext_keys(&store, &keyring).await.unwrap();
+        let sealed = store.contest(&contest).await.unwrap().unwrap();
+        assert_eq!(sealed.private_key_version, Some(2));
+        assert_eq!(sealed.https_token_version, Some(1));
+        assert_eq!(rotate_keys(&store, &keyring).await.unwrap().count, 2);
+        assert_eq!(rotate_keys(&store, &keyring).await.unwrap().count, 0);
+        let history = store.history(&contest, 1).await.unwrap();
+        // The contest and its history still share each secret's ciphertext.
+        let rotated = store.contest(&contest).await.unwrap().unwrap();
+        assert_eq!(history[0].version.private_key, rotated.private_key);
+        assert_eq!(history[0].version.https_token, rotated.https_token);
+        for stored in [
+            store.contest(&contest).await.unwrap().unwrap(),
+            history[0].version.clone(),
+        ] {
+            assert_eq!(
+                keyring
+                    .open(
+                        "42",
+                        stored.private_key.as_ref().unwrap(),
+                        stored.private_key_version
+                    )
+                    .unwrap(),
+                b"private key"
+            );
+            assert_eq!(stored.https_token_version, Some(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_rotating_past_broken_secrets() {
+        let store = MemoryStore::new();
+        for (guild_id, token) in [
+            (41, keyring(1).seal("41", b"token").unwrap()),
+            (42, b"tampered".to_vec()),
+            (43, keyring(1).seal("43", b"token").unwrap()),
+        ] {
+            let contest = ContestRef {
+                guild_id: GuildId(guild_id),
+                name: "default".to_string(),
+            };
+            let version = ConfigVersion {
+                git_remote_url: Some("https://github.com/owner/repo.git".to_string()),
+                contest_rel_path: Some("contest".to_string()),
+                https_token: Some(token),
+                https_token_version: Some(1),
+                ..Default::default()
+            };
+            store.save_contest(&contest, &version).await.unwrap();
+        }
+        let resealed = rotate_keys(&store, &keyring(2)).await.unwrap();
+        assert_eq!(resealed.count, 2);
+        assert_eq!(resealed.failures.len(), 1);
+        assert!(resealed.failures[0].starts_with("the token of guild 42: "));
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::Mutex;
+
+use base64::{engine::general_purpose, Engine};
+use git2::cert::CertHostkey;
+use sha2::{Digest, Sha256};
+
+use crate::git_remote::GitRemote;
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+/// An SSH host key, identified by its OpenSSH `SHA256:` fingerprint. The key
+/// itself is kept when known.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct HostKey {
+    pub key_type: Option<String>,
+    pub public_key: Option<Vec<u8>>,
+    pub fingerprint: String,
+}
+
+/// A host key stored for a guild, either pinned by an admin or seen on first
+/// use.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct KnownHost {
+    #[sqlx(flatten)]
+    pub key: HostKey,
+    pub pinned: bool,
+}
+
+/// The key types OpenSSH writes in `known_hosts` and `ssh-keyscan` output.
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+fn fingerprint_of_hash(hash: &[u8]) -> String {
+    "SHA256:".to_string() + &general_purpose::STANDARD_NO_PAD.encode(hash)
+}
+
+impl HostKey {
+    /// Parses a public key line as printed by `ssh-keyscan` or found in
+    /// `known_hosts`, e.g. `github.com ssh-ed25519 AAAAC3Nz...`: the key type
+    /// comes first, or second after the host names, and is followed by the key
+    /// and an optional comment.
+    pub fn parse_openssh(line: &str) -> Result<Self, TaskPdfWriterBotError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let position = match words.first() {
+            Some(first) if KEY_TYPES.contains(first) => Some(0),
+            _ => words.get(1).filter(|w| KEY_TYPES.contains(w)).map(|_| 1),
+        };
+        let (key_type, encoded) = match position.and_then(|i| Some((words[i], *words.get(i + 1)?)))
+        {
+            Some(pair) => pair,
+            None => Err(MyError::new(&format!(
+                "(probably your fault): expected a public key such as `ssh-ed25519 AAAA...`, optionally after the host names, with one of the key types {}",
+                KEY_TYPES.join(", ")
+            )))?,
+        };
+        let public_key = match general_purpose::STANDARD.decode(encoded) {
+            Ok(k) => k,
+            Err(e) => Err(MyError::new(&format!(
+                "(probably your fault): invalid public key: {}",
+                e
+            )))?,
+        };
+        Ok(HostKey {
+            key_type: Some(key_type.to_string()),
+            fingerprint: fingerprint_of_hash(&Sha256::digest(&public_key)),
+            public_key: Some(public_key),
+        })
+    }
+
+    fn from_cert(cert: &CertHostkey) -> Option<Self> {
+        Some(HostKey {
+            key_type: cert.hostkey_type().map(|t| t.name().to_string()),
+            public_key: cert.hostkey().map(|k| k.to_vec()),
+            fingerprint: fingerprint_of_hash(cert.hash_sha256()?),
+        })
+    }
+}
+
+/// Checks the SSH host key presented during a fetch against the stored one,
+/// or accepts and records it when there is none yet (trust on first use).
+pub struct HostKeyCheck {
+    remote: GitRemote,
+    expected: Option<HostKey>,
+    first_seen: Mutex<Option<HostKey>>,
+}
+
+impl HostKeyCheck {
+    pub fn new(remote: GitRemote, expected: Option<HostKey>) -> Self {
+        HostKeyCheck {
+            remote,
+            expected,
+            first_seen: Mutex::new(None),
+        }
+    }
+
+    pub fn remote(&self) -> &GitRemote {
+        &self.remote
+    }
+
+    /// The key accepted on first use, to be stored once the fetch succeeded.
+    pub fn first_seen(&self) -> Option<HostKey> {
+        self.first_seen.lock().unwrap().clone()
+    }
+
+    /// The `certificate_check` callback of libgit2. Certificates other than
+    /// SSH host keys are left to libgit2.
+    pub fn verify(
+        &self,
+        cert: &git2::cert::Cert<'_>,
+    ) -> Result<git2::CertificateCheckStatus, git2::Error> {
+        let hostkey = match cert.as_hostkey() {
+            Some(h) => h,
+            None => return Ok(git2::CertificateCheckStatus::CertificatePassthrough),
+        };
+        let seen = match HostKey::from_cert(hostkey) {
+            Some(k) => k,
+            None => {
+                return Err(git2::Error::new(
+                    git2::ErrorCode::Certificate,
+                    git2::ErrorClass::Ssh,
+                    format!(
+                        "no SHA-256 fingerprint for the host key of {}",
+                        self.remote.host
+                    ),
+                ))
+            }
+        };
+        match &self.expected {
+            Some(expected) if expected.fingerprint != seen.fingerprint => Err(git2::Error::new(
+                git2::ErrorCode::Certificate,
+                git2::ErrorClass::Ssh,
+                format!(
+                    "WARNING: the SSH host key of {}:{} has changed! Expected {}, got {}. Someone may be intercepting the connection. If the host key was changed on purpose, pin the new one with `/config pin-hostkey`.",
+                    self.remote.host,
+                    self.remote.port_or_default(),
+                    expected.fingerprint,
+                    seen.fingerprint
+                ),
+            )),
+            Some(_) => Ok(git2::CertificateCheckStatus::CertificateOk),
+            None => {
+                *self.first_seen.lock().unwrap() = Some(seen);
+                Ok(git2::CertificateCheckStatus::CertificateOk)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GITHUB_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn fingerprints_like_openssh() {
+        let key = HostKey::parse_openssh(&format!("github.com {}", GITHUB_ED25519)).unwrap();
+        assert_eq!(key.key_type.as_deref(), Some("ssh-ed25519"));
+        assert_eq!(
+            key.fingerprint,
+            "SHA256:+DiY3wvvV6TuJJhbpZisF/zLDA0zPMSvHdkr4UvCOqU"
+        );
+        let keyscan = HostKey::parse_openssh(&format!(
+            "ssh-git.example.com,[ssh-git.example.com]:2222 {} comment",
+            GITHUB_ED25519
+        ))
+        .unwrap();
+        assert_eq!(keyscan, key);
+        assert_eq!(HostKey::parse_openssh(GITHUB_ED25519).unwrap(), key);
+        assert!(HostKey::parse_openssh("github.com").is_err());
+        assert!(HostKey::parse_openssh("ssh-git.example.com AAAAC3Nz").is_err());
+        assert!(
+            HostKey::parse_openssh(&format!("@revoked github.com {}", GITHUB_ED25519)).is_err()
+        );
+        assert!(HostKey::parse_openssh("ssh-ed25519 not-base64!").is_err());
+    }
+}
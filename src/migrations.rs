@@ -0,0 +1,242 @@
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, SqlitePool};
+use tracing::info;
+
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+/// A schema change, applied once and in order.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// Detects a migration edited after it was applied. Line endings are
+    /// ignored, so a checkout with CRLF doesn't count as an edit.
+    fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.replace('\r', "").as_bytes()))
+    }
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        migration!($version, $name, "")
+    };
+    ($version:literal, $name:literal, $dir:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../migrations/", $dir, $name, ".sql")),
+        }
+    };
+}
+
+/// Every migration, oldest first. Never edit or remove one that was released,
+/// add a new one instead. Migrations up to 7 predate this table and were run on
+/// every start, so they are idempotent and also apply to databases created back
+/// then.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_contests"),
+    migration!(2, "0002_pdf_cache"),
+    migration!(3, "0003_known_hosts"),
+    migration!(4, "0004_secrets"),
+    migration!(5, "0005_config_history"),
+    migration!(6, "0006_command_permissions"),
+    migration!(7, "0007_named_contests"),
+];
+
+/// The same migrations in the SQLite dialect, one for one with [`MIGRATIONS`]
+/// so a schema version means the same tables on both databases. SQLite
+/// databases never ran the old schema, so these needn't be idempotent.
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_contests", "sqlite/"),
+    migration!(2, "0002_pdf_cache", "sqlite/"),
+    migration!(3, "0003_known_hosts", "sqlite/"),
+    migration!(4, "0004_secrets", "sqlite/"),
+    migration!(5, "0005_config_history", "sqlite/"),
+    migration!(6, "0006_command_permissions", "sqlite/"),
+    migration!(7, "0007_named_contests", "sqlite/"),
+];
+
+/// Only one instance migrates at a time, the others wait for it. "taskpdf" in ASCII.
+const MIGRATION_LOCK: i64 = 0x0074_6173_6b70_6466;
+
+/// Checks the migrations already applied against `migrations` and returns the
+/// ones still to apply. Refuses a database migrated by a newer version of the
+/// bot, and a migration that was edited or skipped.
+fn pending<'m>(
+    applied: &[(i64, String)],
+    migrations: &'m [Migration],
+) -> Result<&'m [Migration], TaskPdfWriterBotError> {
+    for (i, (version, checksum)) in applied.iter().enumerate() {
+        let migration = match migrations.get(i) {
+            Some(m) => m,
+            None => Err(MyError::new(&format!(
+                "the database is at schema version {}, which this version of the bot doesn't know, upgrade the bot",
+                version
+            )))?,
+        };
+        if migration.version != *version {
+            Err(MyError::new(&format!(
+                "the database skipped migration {} ({}), restore it from a backup",
+                migration.version, migration.name
+            )))?;
+        }
+        if migration.checksum() != *checksum {
+            Err(MyError::new(&format!(
+                "migration {} ({}) was changed after it was applied, revert the change and add a new migration instead",
+                migration.version, migration.name
+            )))?;
+        }
+    }
+    Ok(&migrations[applied.len().min(migrations.len())..])
+}
+
+/// Brings the schema up to date, recording every applied migration in
+/// `schema_migrations`. Run at startup, before anything reads the database.
+/// All pending migrations are applied in one transaction, so a failed one
+/// leaves the schema as it was.
+pub async fn run(database: &sqlx::PgPool) -> Result<(), TaskPdfWriterBotError> {
+    let mut transaction = database.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut transaction)
+        .await?;
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    checksum CHAR(64) NOT NULL,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (version)
+)",
+        )
+        .await?;
+    let applied: Vec<(i64, String)> =
+        sqlx::query_as("SELECT version, checksum FROM schema_migrations ORDER BY version")
+            .fetch_all(&mut transaction)
+            .await?;
+    let pending = pending(&applied, MIGRATIONS)?;
+    for migration in pending {
+        info!(
+            "applying migration {} ({})",
+            migration.version, migration.name
+        );
+        if let Err(e) = transaction.execute(migration.sql).await {
+            Err(failed(migration, e))?;
+        }
+        sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    applied_all(MIGRATIONS, pending);
+    Ok(())
+}
+
+/// [`run`] for a SQLite database, with [`SQLITE_MIGRATIONS`]. There is no
+/// advisory lock, but SQLite lets only one transaction write at a time.
+pub async fn run_sqlite(database: &SqlitePool) -> Result<(), TaskPdfWriterBotError> {
+    let mut transaction = database.begin().await?;
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    checksum CHAR(64) NOT NULL,
+    applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (version)
+)",
+        )
+        .await?;
+    let applied: Vec<(i64, String)> =
+        sqlx::query_as("SELECT version, checksum FROM schema_migrations ORDER BY version")
+            .fetch_all(&mut transaction)
+            .await?;
+    let pending = pending(&applied, SQLITE_MIGRATIONS)?;
+    for migration in pending {
+        info!(
+            "applying migration {} ({})",
+            migration.version, migration.name
+        );
+        if let Err(e) = transaction.execute(migration.sql).await {
+            Err(failed(migration, e))?;
+        }
+        sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES (?1, ?2, ?3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    applied_all(SQLITE_MIGRATIONS, pending);
+    Ok(())
+}
+
+fn failed(migration: &Migration, e: sqlx::Error) -> MyError {
+    MyError::new(&format!(
+        "migration {} ({}) failed: {}",
+        migration.version, migration.name, e
+    ))
+}
+
+fn applied_all(migrations: &[Migration], pending: &[Migration]) {
+    info!(
+        "database schema at version {} ({} migration(s) applied)",
+        migrations.last().map_or(0, |m| m.version),
+        pending.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(count: usize) -> Vec<(i64, String)> {
+        MIGRATIONS[..count]
+            .iter()
+            .map(|m| (m.version, m.checksum()))
+            .collect()
+    }
+
+    #[test]
+    fn migrations_are_numbered_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+            assert!(migration
+                .name
+                .starts_with(&format!("{:04}_", migration.version)));
+        }
+    }
+
+    #[test]
+    fn applies_only_the_pending_migrations() {
+        assert_eq!(pending(&[], MIGRATIONS).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(pending(&applied(2), MIGRATIONS).unwrap()[0].version, 3);
+        assert!(pending(&applied(MIGRATIONS.len()), MIGRATIONS)
+            .unwrap()
+            .is_empty());
+        // Edited, skipped, or from a newer bot.
+        let mut edited = applied(2);
+        edited[1].1 = "0".repeat(64);
+        assert!(pending(&edited, MIGRATIONS).is_err());
+        let mut skipped = applied(3);
+        skipped.remove(1);
+        assert!(pending(&skipped, MIGRATIONS).is_err());
+        assert!(pending(&applied(2), &MIGRATIONS[..1]).is_err());
+    }
+
+    #[test]
+    fn sqlite_has_the_same_migrations() {
+        let versions = |migrations: &[Migration]| -> Vec<(i64, &str)> {
+            migrations.iter().map(|m| (m.version, m.name)).collect()
+        };
+        assert_eq!(versions(SQLITE_MIGRATIONS), versions(MIGRATIONS));
+    }
+}
@@ -0,0 +1,183 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+use git2::Oid;
+use serenity::model::prelude::GuildId;
+use sha2::{Digest, Sha256};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::store::ContestStore;
+use crate::traits::TaskPdfWriterBotError;
+
+/// How many days a cached PDF is kept after it was last served, unless
+/// `PDF_CACHE_DAYS` says otherwise.
+pub const DEFAULT_PDF_CACHE_DAYS: u64 = 30;
+/// How often storing a PDF also looks for PDFs to evict.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The cache key of a rendered task: a SHA-256 over the whole request sent to
+/// the renderer (config, task name and markdown) and the renderer identity.
+/// `serde_json` keeps object keys sorted, so the same request always
+/// serializes the same way.
+pub fn cache_key(request: &serde_json::Value, renderer: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [request.to_string().as_bytes(), renderer.as_bytes()] {
+        // Length-prefixed so that moving bytes between parts changes the key.
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Rendered PDFs stored on disk by cache key, indexed in the store. PDFs not
+/// served for `max_age` are evicted.
+pub struct PdfCache {
+    dir: PathBuf,
+    store: Arc<dyn ContestStore>,
+    max_age: Duration,
+    last_eviction: Mutex<Option<Instant>>,
+}
+
+impl PdfCache {
+    pub fn new(
+        store: Arc<dyn ContestStore>,
+        max_age: Duration,
+    ) -> Result<Self, TaskPdfWriterBotError> {
+        let dir = env::temp_dir().join("task-pdf-writer-v2-bot-pdfs");
+        Self::in_dir(dir, store, max_age)
+    }
+
+    fn in_dir(
+        dir: PathBuf,
+        store: Arc<dyn ContestStore>,
+        max_age: Duration,
+    ) -> Result<Self, TaskPdfWriterBotError> {
+        fs::create_dir_all(&dir)?;
+        Ok(PdfCache {
+            dir,
+            store,
+            max_age,
+            last_eviction: Mutex::new(None),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key.to_string() + ".pdf")
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, TaskPdfWriterBotError> {
+        if !self.store.touch_pdf(key).await? {
+            return Ok(None);
+        }
+        match fs::read(self.path(key)) {
+            Ok(pdf) => Ok(Some(pdf)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // The file is gone (e.g. the temp directory was cleared), forget it.
+                self.store.forget_pdf(key).await?;
+                Ok(None)
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+
+    pub async fn put(
+        &self,
+        key: &str,
+        guild_id: GuildId,
+        task_name: &str,
+        commit: Oid,
+        renderer: &str,
+        pdf: &[u8],
+    ) -> Result<(), TaskPdfWriterBotError> {
+        // Write then rename, so a concurrent `get` never reads half a file.
+        let partial = self.dir.join(Uuid::new_v4().to_string() + ".partial");
+        fs::write(&partial, pdf)?;
+        fs::rename(&partial, self.path(key))?;
+        self.store
+            .index_pdf(key, guild_id, task_name, commit, renderer)
+            .await?;
+        if self.eviction_due() {
+            let evicted = self.evict().await?;
+            if evicted > 0 {
+                info!("evicted {} PDF(s) from the cache", evicted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `EVICTION_INTERVAL` passed since the last eviction, in which
+    /// case the caller evicts.
+    fn eviction_due(&self) -> bool {
+        let mut last_eviction = self.last_eviction.lock().unwrap();
+        match *last_eviction {
+            Some(t) if t.elapsed() < EVICTION_INTERVAL => false,
+            _ => {
+                *last_eviction = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Deletes the PDFs not served for `max_age`. Returns how many were
+    /// deleted.
+    pub async fn evict(&self) -> Result<usize, TaskPdfWriterBotError> {
+        let expired = self
+            .store
+            .expire_pdfs(self.max_age.as_secs() as i64)
+            .await?;
+        for key in &expired {
+            match fs::remove_file(self.path(key)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use serde_json::json;
+
+    #[test]
+    fn keys_depend_on_the_whole_request() {
+        let request = |task_name: &str| json!({"contest_name": "TOI", "task_name": task_name, "content": "stub"});
+        let key = cache_key(&request("sum"), "mock");
+        assert_eq!(key, cache_key(&request("sum"), "mock"));
+        assert_ne!(key, cache_key(&request("product"), "mock"));
+        assert_ne!(key, cache_key(&request("sum"), "http:https://example.com"));
+    }
+
+    #[tokio::test]
+    async fn evicts_pdfs_not_served_for_max_age() {
+        let dir = env::temp_dir().join(format!("pdf-cache-test-{}", Uuid::new_v4()));
+        let store = Arc::new(MemoryStore::new());
+        let put = |cache: PdfCache| async move {
+            cache
+                .put("key", GuildId(1), "sum", Oid::zero(), "mock", b"%PDF")
+                .await
+                .unwrap();
+            cache
+        };
+        let kept =
+            put(PdfCache::in_dir(dir.clone(), store.clone(), Duration::from_secs(3600)).unwrap())
+                .await;
+        assert_eq!(kept.evict().await.unwrap(), 0);
+        assert_eq!(
+            kept.get("key").await.unwrap().as_deref(),
+            Some(&b"%PDF"[..])
+        );
+        let expiring = PdfCache::in_dir(dir.clone(), store, Duration::ZERO).unwrap();
+        assert_eq!(expiring.evict().await.unwrap(), 1);
+        assert!(!expiring.path("key").exists());
+        assert_eq!(expiring.get("key").await.unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
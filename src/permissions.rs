@@ -0,0 +1,249 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::prelude::{GuildId, Permissions, RoleId, UserId};
+use shuttle_secrets::SecretStore;
+
+use crate::store::ContestStore;
+use crate::traits::{CommandHandlerData, MyError, TaskPdfWriterBotError};
+
+/// What a role may be allowed to do with the bot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// `/config` and `/contest`.
+    Configure,
+    /// `/genpdf` and `/booklet`.
+    Generate,
+    /// `/admin`, including managing these permissions.
+    Administer,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::Configure,
+        Capability::Generate,
+        Capability::Administer,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Configure => "configure",
+            Capability::Generate => "generate",
+            Capability::Administer => "administer",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Capability::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// The capability a command needs, `None` for commands anyone may use.
+    pub fn for_command(command: &str) -> Option<Self> {
+        match command {
+            "config" | "contest" => Some(Capability::Configure),
+            "genpdf" | "booklet" => Some(Capability::Generate),
+            "admin" => Some(Capability::Administer),
+            _ => None,
+        }
+    }
+
+    /// The Discord permissions needed while the guild hasn't granted the
+    /// capability to any role. Also registered as the command's
+    /// `default_member_permissions`, so Discord hides it from other members.
+    pub fn default_permissions(self) -> Permissions {
+        match self {
+            Capability::Configure => Permissions::MANAGE_GUILD,
+            Capability::Generate => Permissions::empty(),
+            Capability::Administer => Permissions::ADMINISTRATOR,
+        }
+    }
+
+    /// Sets the `default_member_permissions` of a command needing this
+    /// capability.
+    pub fn restrict(self, command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+        // Empty permissions would hide the command from everyone but admins.
+        match self.default_permissions().is_empty() {
+            true => command,
+            false => command.default_member_permissions(self.default_permissions()),
+        }
+    }
+}
+
+/// Whether a member may use a capability. Administrators always may, so a
+/// guild can't lock itself out. Once roles are granted the capability, only
+/// members with one of them may; until then, the default permissions decide.
+fn is_allowed(
+    capability: Capability,
+    permissions: Permissions,
+    roles: &[RoleId],
+    granted: &[RoleId],
+) -> bool {
+    if permissions.administrator() {
+        return true;
+    }
+    match granted.is_empty() {
+        true => permissions.contains(capability.default_permissions()),
+        false => roles.iter().any(|r| granted.contains(r)),
+    }
+}
+
+/// Refuses the command if the member who invoked it lacks the capability it
+/// needs. Discord's `default_member_permissions` only hides commands and can
+/// be overridden by the guild, so this is checked on every command.
+pub async fn check(data: &CommandHandlerData<'_>) -> Result<(), TaskPdfWriterBotError> {
+    let capability = match Capability::for_command(&data.command.data.name) {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let (guild_id, member) = match (data.command.guild_id, &data.command.member) {
+        (Some(g), Some(m)) => (g, m),
+        _ => Err(MyError::new("this command can only be used in a server"))?,
+    };
+    let permissions = member.permissions.unwrap_or_else(Permissions::empty);
+    authorize(data.store, guild_id, capability, permissions, &member.roles).await
+}
+
+/// Refuses a member with these permissions and roles the capability, given
+/// the roles the guild granted it to.
+async fn authorize(
+    store: &dyn ContestStore,
+    guild_id: GuildId,
+    capability: Capability,
+    permissions: Permissions,
+    roles: &[RoleId],
+) -> Result<(), TaskPdfWriterBotError> {
+    let granted = store.granted_roles(guild_id, capability).await?;
+    if !is_allowed(capability, permissions, roles, &granted) {
+        let needed = match granted.is_empty() {
+            true => format!("the {} permission", capability.default_permissions()),
+            false => {
+                let roles: Vec<String> = granted.iter().map(|r| format!("<@&{}>", r)).collect();
+                format!("one of the roles {}", roles.join(", "))
+            }
+        };
+        Err(MyError::new(&format!(
+            "you need {} to {} with this bot",
+            needed,
+            capability.name()
+        )))?;
+    }
+    Ok(())
+}
+
+/// The users who run the bot, from the comma-separated Discord user IDs in
+/// the `OWNER_IDS` secret. Only they may do what affects every guild, like
+/// rotating the master key.
+#[derive(Debug, Clone, Default)]
+pub struct Owners(Vec<UserId>);
+
+impl Owners {
+    pub fn from_secrets(secret_store: &SecretStore) -> Result<Self, TaskPdfWriterBotError> {
+        Owners::parse(&secret_store.get("OWNER_IDS").unwrap_or_default())
+    }
+
+    fn parse(list: &str) -> Result<Self, TaskPdfWriterBotError> {
+        let mut owners = Vec::new();
+        for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            match id.parse() {
+                Ok(id) => owners.push(UserId(id)),
+                Err(_) => Err(MyError::new(&format!(
+                    "'OWNER_IDS' has an invalid user ID '{}'",
+                    id
+                )))?,
+            }
+        }
+        Ok(Owners(owners))
+    }
+
+    /// Refuses anyone but the owners.
+    pub fn check(&self, user: UserId) -> Result<(), TaskPdfWriterBotError> {
+        match (self.0.contains(&user), self.0.is_empty()) {
+            (true, _) => Ok(()),
+            (false, true) => Err(MyError::new(
+                "only the bot's owners may do this, and none is set in `OWNER_IDS`",
+            ))?,
+            (false, false) => Err(MyError::new("only the bot's owners may do this"))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn granted_roles_replace_the_default_permissions() {
+        let manager = Permissions::MANAGE_GUILD;
+        let member = Permissions::SEND_MESSAGES;
+        let setters = [RoleId(1)];
+        assert!(is_allowed(Capability::Configure, manager, &[], &[]));
+        assert!(!is_allowed(Capability::Configure, member, &[], &[]));
+        assert!(is_allowed(Capability::Generate, member, &[], &[]));
+        assert!(!is_allowed(Capability::Configure, manager, &[], &setters));
+        assert!(is_allowed(
+            Capability::Configure,
+            member,
+            &setters,
+            &setters
+        ));
+        assert!(is_allowed(
+            Capability::Administer,
+            Permissions::ADMINISTRATOR,
+            &[],
+            &setters
+        ));
+        assert_eq!(Capability::parse("generate"), Some(Capability::Generate));
+        assert_eq!(Capability::for_command("ping"), None);
+    }
+
+    #[tokio::test]
+    async fn checks_the_roles_granted_in_the_store() {
+        let store = MemoryStore::new();
+        let guild_id = GuildId(42);
+        let member = Permissions::SEND_MESSAGES;
+        let configure = |permissions, roles: &'static [RoleId]| {
+            authorize(&store, guild_id, Capability::Configure, permissions, roles)
+        };
+        assert!(configure(Permissions::MANAGE_GUILD, &[]).await.is_ok());
+        let refused = configure(member, &[]).await.unwrap_err();
+        assert!(refused.to_string().contains("Manage Guilds"), "{}", refused);
+
+        assert!(store
+            .grant(guild_id, Capability::Configure, RoleId(1))
+            .await
+            .unwrap());
+        assert!(configure(member, &[RoleId(1)]).await.is_ok());
+        let refused = configure(Permissions::MANAGE_GUILD, &[RoleId(2)])
+            .await
+            .unwrap_err();
+        assert!(refused.to_string().contains("<@&1>"), "{}", refused);
+        assert!(configure(Permissions::ADMINISTRATOR, &[]).await.is_ok());
+        // Other guilds and capabilities keep their defaults.
+        assert!(authorize(
+            &store,
+            GuildId(43),
+            Capability::Configure,
+            member,
+            &[RoleId(1)]
+        )
+        .await
+        .is_err());
+        assert!(authorize(
+            &store,
+            guild_id,
+            Capability::Administer,
+            member,
+            &[RoleId(1)]
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn only_owners_pass() {
+        let owners = Owners::parse(" 1, 2,").unwrap();
+        assert!(owners.check(UserId(2)).is_ok());
+        assert!(owners.check(UserId(3)).is_err());
+        assert!(Owners::default().check(UserId(1)).is_err());
+        assert!(Owners::parse("1,me").is_err());
+    }
+}
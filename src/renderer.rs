@@ -0,0 +1,322 @@
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine};
+use serenity::async_trait;
+use shuttle_secrets::SecretStore;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+pub const DEFAULT_RENDERER_URL: &str =
+    "https://973i5k6wjg.execute-api.ap-southeast-1.amazonaws.com/dev/genpdf";
+/// How long a renderer gets to answer before the task is given up on.
+const RENDER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A backend that turns a task-pdf-writer-v2 request (the contest `config.json`
+/// with `content` and `task_name` filled in) into PDF bytes.
+#[async_trait]
+pub trait PdfRenderer: Send + Sync {
+    /// A short description of the backend, e.g. `http:<url>`.
+    fn identity(&self) -> String;
+    async fn render(&self, request: &serde_json::Value) -> Result<Vec<u8>, TaskPdfWriterBotError>;
+}
+
+/// Posts the request to a task-pdf-writer-v2 HTTP endpoint, which answers with
+/// `{"message": "<base64 pdf>"}`.
+pub struct HttpRenderer {
+    url: String,
+    client: reqwest::Client,
+}
+impl HttpRenderer {
+    pub fn new(url: String) -> Self {
+        HttpRenderer {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+    /// An endpoint chosen by a guild: `host` is only connected to at the
+    /// `addresses` it was checked at (when not empty), and redirects are not
+    /// followed.
+    pub fn pinned(
+        url: String,
+        host: &str,
+        addresses: &[SocketAddr],
+    ) -> Result<Self, TaskPdfWriterBotError> {
+        let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        if !addresses.is_empty() {
+            builder = builder.resolve_to_addrs(host, addresses);
+        }
+        Ok(HttpRenderer {
+            url,
+            client: builder.build()?,
+        })
+    }
+}
+
+#[async_trait]
+impl PdfRenderer for HttpRenderer {
+    fn identity(&self) -> String {
+        format!("http:{}", self.url)
+    }
+    async fn render(&self, request: &serde_json::Value) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+        let resp = self
+            .client
+            .post(self.url.as_str())
+            .body(request.to_string())
+            .timeout(RENDER_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let resp_obj: serde_json::Value = serde_json::from_str(resp.as_str())?;
+        let resp_obj = match resp_obj.as_object() {
+            Some(obj) => obj,
+            None => Err(MyError::new("JSON object not found"))?,
+        };
+        let message = match resp_obj.get("message") {
+            Some(m) => m,
+            None => Err(MyError::new("message doesn't exist"))?,
+        };
+        if let serde_json::Value::String(content_base64) = message {
+            match general_purpose::STANDARD.decode(content_base64) {
+                Ok(s) => Ok(s),
+                Err(e) => Err(MyError::new(
+                    ("[base64]".to_string() + e.to_string().as_str()).as_str(),
+                ))?,
+            }
+        } else {
+            Err(MyError::new("message in json is not a string"))?
+        }
+    }
+}
+
+/// Runs a local renderer program, writing the request JSON to its stdin and
+/// reading the PDF from its stdout.
+pub struct SubprocessRenderer {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+impl SubprocessRenderer {
+    pub fn new(program: String, args: Vec<String>) -> Self {
+        SubprocessRenderer {
+            program,
+            args,
+            timeout: RENDER_TIMEOUT,
+        }
+    }
+}
+
+#[async_trait]
+impl PdfRenderer for SubprocessRenderer {
+    fn identity(&self) -> String {
+        format!("local:{} {}", self.program, self.args.join(" "))
+    }
+    async fn render(&self, request: &serde_json::Value) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        // Written while the output is read, so that a renderer that writes
+        // before it has read everything can't block on a full pipe.
+        let stdin = child.stdin.take();
+        let input = request.to_string();
+        let written = tokio::spawn(async move {
+            match stdin {
+                Some(mut stdin) => stdin.write_all(input.as_bytes()).await,
+                None => Ok(()),
+            }
+        });
+        // Dropping the child on timeout kills it.
+        let output = match timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => output?,
+            Err(_) => Err(MyError::new(&format!(
+                "renderer took longer than {}s",
+                self.timeout.as_secs()
+            )))?,
+        };
+        if !output.status.success() {
+            Err(MyError::new(&format!(
+                "renderer exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )))?;
+        }
+        // A renderer that succeeds without reading its whole request closed
+        // the pipe on purpose.
+        match written.await.map_err(std::io::Error::from)? {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e)?,
+            _ => {}
+        }
+        if !output.stdout.starts_with(b"%PDF") {
+            Err(MyError::new("renderer output is not a PDF"))?;
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Produces a one-page placeholder PDF without contacting anything. Useful when
+/// running the bot offline.
+pub struct MockRenderer;
+
+#[async_trait]
+impl PdfRenderer for MockRenderer {
+    fn identity(&self) -> String {
+        "mock".to_string()
+    }
+    async fn render(&self, request: &serde_json::Value) -> Result<Vec<u8>, TaskPdfWriterBotError> {
+        let task_name = request["task_name"].as_str().unwrap_or("untitled");
+        Ok(placeholder_pdf(task_name))
+    }
+}
+
+fn placeholder_pdf(title: &str) -> Vec<u8> {
+    let title: String = title
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .filter(|c| !matches!(c, '(' | ')' | '\\'))
+        .collect();
+    let text = format!("BT /F1 24 Tf 72 720 Td ({}) Tj ET", title);
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+        "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] /Resources << /Font << /F1 4 0 R >> >> /Contents 5 0 R >>".to_string(),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+        format!("<< /Length {} >>\nstream\n{}\nendstream", text.len(), text),
+    ];
+    let mut out = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref_offset = out.len();
+    out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        out.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+    out
+}
+
+/// Builds the deployment-wide renderer from the `RENDERER` secret (`http`,
+/// `local` or `mock`, defaults to `http`). `RENDERER_URL` sets the HTTP
+/// endpoint and `RENDERER_COMMAND` the local program and its arguments.
+pub fn renderer_from_secrets(
+    secret_store: &SecretStore,
+) -> Result<Arc<dyn PdfRenderer>, TaskPdfWriterBotError> {
+    renderer_from(|key| secret_store.get(key))
+}
+
+fn renderer_from(
+    secret: impl Fn(&str) -> Option<String>,
+) -> Result<Arc<dyn PdfRenderer>, TaskPdfWriterBotError> {
+    let kind = secret("RENDERER").unwrap_or_else(|| "http".to_string());
+    match kind.as_str() {
+        "http" => Ok(Arc::new(HttpRenderer::new(
+            secret("RENDERER_URL").unwrap_or_else(|| DEFAULT_RENDERER_URL.to_string()),
+        ))),
+        "local" => {
+            let command = match secret("RENDERER_COMMAND") {
+                Some(c) => c,
+                None => Err(MyError::new("'RENDERER_COMMAND' was not found"))?,
+            };
+            let mut words = command.split_whitespace().map(|w| w.to_string());
+            let program = match words.next() {
+                Some(p) => p,
+                None => Err(MyError::new("'RENDERER_COMMAND' is empty"))?,
+            };
+            Ok(Arc::new(SubprocessRenderer::new(program, words.collect())))
+        }
+        "mock" => Ok(Arc::new(MockRenderer)),
+        _ => Err(MyError::new(&format!("unknown renderer '{}'", kind)))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn renderer(secrets: &[(&str, &str)]) -> Result<Arc<dyn PdfRenderer>, TaskPdfWriterBotError> {
+        let secrets: BTreeMap<String, String> = secrets
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        renderer_from(|key| secrets.get(key).cloned())
+    }
+
+    #[test]
+    fn selects_the_renderer_from_secrets() {
+        assert_eq!(
+            renderer(&[]).unwrap().identity(),
+            format!("http:{}", DEFAULT_RENDERER_URL)
+        );
+        assert_eq!(
+            renderer(&[("RENDERER_URL", "https://renderer.example.com")])
+                .unwrap()
+                .identity(),
+            "http:https://renderer.example.com"
+        );
+        assert_eq!(
+            renderer(&[("RENDERER", "local"), ("RENDERER_COMMAND", "render --fast")])
+                .unwrap()
+                .identity(),
+            "local:render --fast"
+        );
+        assert_eq!(
+            renderer(&[("RENDERER", "mock")]).unwrap().identity(),
+            "mock"
+        );
+        assert!(renderer(&[("RENDERER", "local")]).is_err());
+        assert!(renderer(&[("RENDERER", "latex")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn subprocess_reads_and_writes_at_once_and_times_out() {
+        // More than a pipe holds each way, with the output written first.
+        let request = serde_json::json!({ "content": "x".repeat(1 << 20) });
+        let renderer = SubprocessRenderer::new(
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "printf %%PDF; head -c 1048576 /dev/zero; cat > /dev/null".to_string(),
+            ],
+        );
+        let pdf = renderer.render(&request).await.unwrap();
+        assert_eq!(pdf.len(), 4 + (1 << 20));
+        let renderer = SubprocessRenderer {
+            timeout: Duration::from_millis(100),
+            ..SubprocessRenderer::new("sleep".to_string(), vec!["10".to_string()])
+        };
+        assert!(renderer.render(&request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_renders_a_pdf_titled_by_the_task() {
+        let pdf = MockRenderer
+            .render(&serde_json::json!({"task_name": "sum (easy)", "content": "# Sum"}))
+            .await
+            .unwrap();
+        let document = lopdf::Document::load_mem(&pdf).unwrap();
+        assert_eq!(document.get_pages().len(), 1);
+        let text = document.extract_text(&[1]).unwrap();
+        assert!(text.contains("sum easy"), "{}", text);
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{env, ffi::OsStr, fs};
+
+use git2::{ErrorClass, FetchPrune, ObjectType, Oid, Repository, ResetType};
+use serenity::model::prelude::GuildId;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{info, warn};
+
+use crate::credentials::Credentials;
+use crate::known_hosts::HostKeyCheck;
+use crate::traits::{MyError, TaskPdfWriterBotError};
+use crate::util::{fetch_options, normalize_relative};
+use crate::workdir::WorkDir;
+
+pub const DEFAULT_REPO_CACHE_SIZE: usize = 16;
+
+/// Keeps one clone per guild and remote on disk and brings it up to date with
+/// a fetch and a hard reset instead of cloning again. Contests of a guild
+/// sharing a repository share its clone. At most `capacity` clones are kept;
+/// the least recently used ones are removed first.
+pub struct RepoCache {
+    root: PathBuf,
+    capacity: usize,
+    /// Directory names of the clones, least recently used first.
+    entries: Mutex<Vec<(String, Arc<AsyncMutex<()>>)>>,
+}
+
+/// The directory of a clone: the guild, then a hash of the URL so the name
+/// stays short and safe.
+fn clone_name(guild_id: GuildId, url: &str) -> String {
+    let hash = hex::encode(Sha256::digest(url.as_bytes()));
+    format!("{}-{}", guild_id, &hash[..16])
+}
+
+impl RepoCache {
+    pub fn new(capacity: usize) -> Result<Self, TaskPdfWriterBotError> {
+        Self::in_dir(
+            env::temp_dir().join("task-pdf-writer-v2-bot-repos"),
+            capacity,
+        )
+    }
+
+    fn in_dir(root: PathBuf, capacity: usize) -> Result<Self, TaskPdfWriterBotError> {
+        fs::create_dir_all(&root)?;
+        // Pick up the clones left by a previous run, oldest first.
+        let mut existing = Vec::new();
+        for entry in root.read_dir()? {
+            let entry = entry?;
+            let name = match entry.file_name().to_str() {
+                Some(n) => n.to_string(),
+                None => continue,
+            };
+            existing.push((entry.metadata()?.modified()?, name));
+        }
+        existing.sort();
+        let entries = existing
+            .into_iter()
+            .map(|(_, name)| (name, Arc::new(AsyncMutex::new(()))))
+            .collect();
+        Ok(RepoCache {
+            root,
+            capacity: capacity.max(1),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn lock_for(&self, name: &str) -> Arc<AsyncMutex<()>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.iter().position(|(n, _)| n == name) {
+            Some(i) => entries.remove(i),
+            None => (name.to_string(), Arc::new(AsyncMutex::new(()))),
+        };
+        let lock = entry.1.clone();
+        entries.push(entry);
+        lock
+    }
+
+    /// Brings the guild's clone of `url` up to date and copies `subdir` of the
+    /// commit `reference` points to (a branch, tag or commit, the remote's
+    /// default branch if `None`) into a fresh work directory, under `contest/`.
+    /// The clone's lock is only held while doing so, so jobs never see each
+    /// other's files. The remote is authenticated to with `credentials` and
+    /// SSH host keys are checked with `host_check`.
+    /// Returns the work directory and the resolved commit.
+    pub async fn snapshot(
+        &self,
+        guild_id: GuildId,
+        url: String,
+        credentials: Credentials,
+        reference: Option<String>,
+        subdir: String,
+        host_check: Arc<HostKeyCheck>,
+    ) -> Result<(WorkDir, Oid), TaskPdfWriterBotError> {
+        let name = clone_name(guild_id, &url);
+        let _guard = self.lock_for(&name).lock_owned().await;
+        let repo_dir = self.root.join(&name);
+        let job = WorkDir::new()?;
+        let synced = {
+            let repo_dir = repo_dir.clone();
+            let dest = job.path().join("contest");
+            tokio::task::spawn_blocking(move || {
+                sync_repo(&repo_dir, &url, &credentials, &host_check)?;
+                extract_commit(&repo_dir, reference.as_deref(), &subdir, &dest)
+            })
+            .await
+        };
+        let commit = match synced {
+            Ok(result) => result?,
+            Err(e) => Err(MyError::new(&format!("git task failed: {}", e)))?,
+        };
+        self.evict(&name);
+        Ok((job, commit))
+    }
+
+    /// Removes the least recently used clones that are over capacity, skipping
+    /// the ones in use. A clone stays registered, with its lock held, until its
+    /// directory is gone, so a job that wants it meanwhile waits and then
+    /// clones it again.
+    fn evict(&self, current: &str) {
+        let victims: Vec<_> = {
+            let entries = self.entries.lock().unwrap();
+            let excess = entries.len().saturating_sub(self.capacity);
+            entries
+                .iter()
+                .filter(|(name, _)| name != current)
+                .filter_map(|(name, lock)| {
+                    Some((name.clone(), lock.clone().try_lock_owned().ok()?))
+                })
+                .take(excess)
+                .collect()
+        };
+        for (name, guard) in victims {
+            info!("evicting the cached repository {}", name);
+            match fs::remove_dir_all(self.root.join(&name)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("cannot evict the cached repository {}: {}", name, e);
+                    continue;
+                }
+            }
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|(n, _)| *n == name) {
+                // Only the list and `guard` hold the lock when no job waits for it.
+                if Arc::strong_count(&entries[i].1) == 2 {
+                    entries.remove(i);
+                }
+            }
+            drop(guard);
+        }
+    }
+
+    #[cfg(test)]
+    fn is_cached(&self, name: &str) -> bool {
+        self.entries.lock().unwrap().iter().any(|(n, _)| n == name) && self.root.join(name).exists()
+    }
+}
+
+/// What a remote advertises, as listed by [`ls_remote`].
+pub struct RemoteRefs {
+    /// Like `refs/heads/main`, if the remote has a default branch.
+    pub default_branch: Option<String>,
+    pub branches: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+/// Connects to `url` and lists its branches and tags without fetching any
+/// object, like `git ls-remote`.
+pub fn ls_remote(
+    url: &str,
+    credentials: &Credentials,
+    host_check: &Arc<HostKeyCheck>,
+) -> Result<RemoteRefs, TaskPdfWriterBotError> {
+    let scratch = WorkDir::new()?;
+    let repo = Repository::init_bare(scratch.path())?;
+    let mut remote = repo.remote_anonymous(url)?;
+    // Without refspecs the download stops after reading the advertised refs.
+    // It still goes through `fetch_options`, so redirects are refused.
+    remote.download(
+        &[] as &[&str],
+        Some(&mut fetch_options(credentials, host_check)),
+    )?;
+    let mut refs = RemoteRefs {
+        default_branch: remote
+            .default_branch()
+            .ok()
+            .map(|b| String::from_utf8_lossy(&b).to_string()),
+        branches: Vec::new(),
+        tags: Vec::new(),
+    };
+    for head in remote.list()? {
+        if let Some(branch) = head.name().strip_prefix("refs/heads/") {
+            refs.branches.push(branch.to_string());
+        } else if let Some(tag) = head.name().strip_prefix("refs/tags/") {
+            // Annotated tags are advertised twice, once peeled.
+            if !tag.ends_with("^{}") {
+                refs.tags.push(tag.to_string());
+            }
+        }
+    }
+    remote.disconnect()?;
+    Ok(refs)
+}
+
+/// Errors that mean the clone on disk is broken rather than the remote being
+/// unreachable.
+fn is_corrupted(e: &git2::Error) -> bool {
+    matches!(
+        e.class(),
+        ErrorClass::Repository
+            | ErrorClass::Reference
+            | ErrorClass::Odb
+            | ErrorClass::Object
+            | ErrorClass::Index
+            | ErrorClass::Zlib
+            | ErrorClass::Checkout
+            | ErrorClass::Filesystem
+            // E.g. a reference whose target object is missing.
+            | ErrorClass::Invalid
+    )
+}
+
+fn sync_repo(
+    workdir: &Path,
+    url: &str,
+    credentials: &Credentials,
+    host_check: &Arc<HostKeyCheck>,
+) -> Result<(), TaskPdfWriterBotError> {
+    if workdir.exists() {
+        match fetch_repo(workdir, url, credentials, host_check) {
+            Ok(()) => return Ok(()),
+            Err(e) if is_corrupted(&e) => {
+                warn!("recloning {}: {}", workdir.display(), e);
+                fs::remove_dir_all(workdir)?;
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options(credentials, host_check));
+    match builder.clone(url, workdir) {
+        // The clone only follows tags of the default branch, fetch the rest.
+        Ok(_) => Ok(fetch_repo(workdir, url, credentials, host_check)?),
+        Err(e)
+            if matches!(credentials, Credentials::Anonymous)
+                && e.code() != git2::ErrorCode::Certificate =>
+        {
+            Err(MyError::new(
+                ("(probably your fault if the repo is private and you haven't set the private key or token) "
+                    .to_string()
+                    + e.to_string().as_str())
+                .as_str(),
+            ))?
+        }
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Fetches every branch and tag, then hard resets the working tree to the
+/// remote's default branch.
+fn fetch_repo(
+    workdir: &Path,
+    url: &str,
+    credentials: &Credentials,
+    host_check: &Arc<HostKeyCheck>,
+) -> Result<(), git2::Error> {
+    let repo = Repository::open(workdir)?;
+    let default_branch = {
+        let mut remote = repo.find_remote("origin")?;
+        if remote.url() != Some(url) {
+            // The guild changed its URL, start over with a fresh clone.
+            Err(git2::Error::new(
+                git2::ErrorCode::GenericError,
+                ErrorClass::Repository,
+                "the remote URL has changed",
+            ))?;
+        }
+        let mut fo = fetch_options(credentials, host_check);
+        fo.prune(FetchPrune::On);
+        remote.fetch(
+            &[
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Some(&mut fo),
+            None,
+        )?;
+        let default_branch = remote.default_branch()?;
+        String::from_utf8_lossy(&default_branch).to_string()
+    };
+    let tracking = match default_branch.strip_prefix("refs/heads/") {
+        Some(branch) => format!("refs/remotes/origin/{}", branch),
+        None => default_branch,
+    };
+    let head = repo.find_reference(&tracking)?.peel_to_commit()?;
+    repo.set_head_detached(head.id())?;
+    repo.reset(head.as_object(), ResetType::Hard, None)?;
+    repo.cleanup_state()?;
+    Ok(())
+}
+
+fn resolve_commit<'r>(
+    repo: &'r Repository,
+    reference: &str,
+) -> Result<git2::Commit<'r>, TaskPdfWriterBotError> {
+    let candidates = [
+        format!("refs/remotes/origin/{}", reference),
+        format!("refs/tags/{}", reference),
+    ];
+    for candidate in candidates {
+        if let Ok(r) = repo.find_reference(&candidate) {
+            return Ok(r.peel_to_commit()?);
+        }
+    }
+    match repo
+        .revparse_single(reference)
+        .and_then(|o| o.peel_to_commit())
+    {
+        Ok(commit) => Ok(commit),
+        Err(_) => Err(MyError::new(&format!(
+            "(probably your fault): cannot find a branch, tag or commit named '{}'",
+            reference
+        )))?,
+    }
+}
+
+/// Writes `subdir` of the resolved commit to `dest`, reading from the object
+/// database so uncommitted changes in the clone never leak in.
+fn extract_commit(
+    repo_dir: &Path,
+    reference: Option<&str>,
+    subdir: &str,
+    dest: &Path,
+) -> Result<Oid, TaskPdfWriterBotError> {
+    let repo = Repository::open(repo_dir)?;
+    let commit = match reference {
+        Some(r) => resolve_commit(&repo, r)?,
+        None => repo.head()?.peel_to_commit()?,
+    };
+    let mut tree = commit.tree()?;
+    let subdir = normalize_relative(Path::new(subdir))?;
+    if !subdir.as_os_str().is_empty() {
+        let found = tree
+            .get_path(&subdir)
+            .and_then(|entry| entry.to_object(&repo)?.peel_to_tree());
+        tree = match found {
+            Ok(tree) => tree,
+            Err(e) => {
+                return Err(TaskPdfWriterBotError::MissingDirectory(MyError::new(
+                    &format!(
+                        "(probably your fault): {} is not a directory in the repository ({})",
+                        subdir.display(),
+                        e
+                    ),
+                )))
+            }
+        };
+    }
+    extract_tree(&repo, &tree, dest)?;
+    Ok(commit.id())
+}
+
+/// Writes `tree` to `dest`, which must not exist yet. Entry names come from
+/// the remote, so names that would leave their directory and duplicate names
+/// are refused, and nothing is ever written through a symlink: directories
+/// and files are created without following links, and symlinks are only
+/// created once everything else is written.
+fn extract_tree(
+    repo: &Repository,
+    tree: &git2::Tree,
+    dest: &Path,
+) -> Result<(), TaskPdfWriterBotError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut symlinks = Vec::new();
+    write_tree(repo, tree, dest, &mut symlinks)?;
+    for (target, path) in symlinks {
+        std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is a plain file name, not `.`, `..` or a path.
+fn is_valid_entry_name(name: &[u8]) -> bool {
+    !name.is_empty() && name != b"." && name != b".." && !name.contains(&b'/') && !name.contains(&0)
+}
+
+fn write_tree(
+    repo: &Repository,
+    tree: &git2::Tree,
+    dest: &Path,
+    symlinks: &mut Vec<(Vec<u8>, PathBuf)>,
+) -> Result<(), TaskPdfWriterBotError> {
+    // Fails if anything, a symlink included, is already there.
+    fs::create_dir(dest)?;
+    let mut seen = BTreeSet::new();
+    for entry in tree.iter() {
+        let name = entry.name_bytes();
+        if !is_valid_entry_name(name) || !seen.insert(name.to_vec()) {
+            Err(MyError::new(&format!(
+                "(probably your fault): the repository has an invalid or duplicate file name `{}`",
+                String::from_utf8_lossy(name)
+            )))?;
+        }
+        let path = dest.join(OsStr::from_bytes(name));
+        match entry.kind() {
+            Some(ObjectType::Tree) => write_tree(
+                repo,
+                &entry.to_object(repo)?.peel_to_tree()?,
+                &path,
+                symlinks,
+            )?,
+            Some(ObjectType::Blob) => {
+                let blob = entry.to_object(repo)?.peel_to_blob()?;
+                if entry.filemode() == 0o120000 {
+                    symlinks.push((blob.content().to_vec(), path));
+                } else {
+                    // `create_new` never follows a symlink, even a dangling one.
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&path)?
+                        .write_all(blob.content())?;
+                }
+            }
+            // Submodules are not fetched.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_remote::GitRemote;
+
+    #[test]
+    fn keeps_a_clone_per_guild_and_remote() {
+        let qualifier = clone_name(GuildId(42), "https://github.com/owner/qualifier.git");
+        assert!(qualifier.starts_with("42-"));
+        assert_eq!(qualifier.len(), "42-".len() + 16);
+        assert_ne!(
+            qualifier,
+            clone_name(GuildId(42), "https://github.com/owner/final.git")
+        );
+        assert_ne!(
+            qualifier,
+            clone_name(GuildId(43), "https://github.com/owner/qualifier.git")
+        );
+    }
+
+    /// A tree object written as is, so it may hold entries `git` would refuse.
+    fn raw_tree(repo: &Repository, entries: &[(&str, &str, Oid)]) -> Oid {
+        let mut raw = Vec::new();
+        for (mode, name, oid) in entries {
+            raw.extend_from_slice(format!("{} {}\0", mode, name).as_bytes());
+            raw.extend_from_slice(oid.as_bytes());
+        }
+        repo.odb().unwrap().write(ObjectType::Tree, &raw).unwrap()
+    }
+
+    #[test]
+    fn never_writes_outside_the_destination() {
+        let origin = WorkDir::new().unwrap();
+        let repo = Repository::init_bare(origin.path()).unwrap();
+        let outside = WorkDir::new().unwrap();
+        let blob = repo.blob(b"pwned").unwrap();
+        let target = repo.blob(outside.path().as_os_str().as_bytes()).unwrap();
+        let inner = raw_tree(&repo, &[("100644", "f", blob)]);
+        let malicious = [
+            // A symlink out, then a directory of the same name written through it.
+            raw_tree(&repo, &[("120000", "d", target), ("40000", "d", inner)]),
+            raw_tree(&repo, &[("40000", "..", inner)]),
+            raw_tree(&repo, &[("100644", "a/../../f", blob)]),
+        ];
+        for tree in malicious {
+            let job = WorkDir::new().unwrap();
+            let tree = repo.find_tree(tree).unwrap();
+            assert!(extract_tree(&repo, &tree, &job.path().join("contest")).is_err());
+            assert!(!outside.path().join("f").exists());
+            assert!(!job.path().join("f").exists());
+        }
+        // A symlink is still extracted, after the files.
+        let job = WorkDir::new().unwrap();
+        let fine = raw_tree(&repo, &[("100644", "f", blob), ("120000", "link", blob)]);
+        let dest = job.path().join("contest");
+        extract_tree(&repo, &repo.find_tree(fine).unwrap(), &dest).unwrap();
+        assert_eq!(fs::read(dest.join("f")).unwrap(), b"pwned");
+        assert!(dest.join("link").symlink_metadata().unwrap().is_symlink());
+    }
+
+    /// A repository with one commit holding `contest/task.md`.
+    fn origin(content: &str) -> WorkDir {
+        let origin = WorkDir::new().unwrap();
+        fs::create_dir(origin.path().join("contest")).unwrap();
+        fs::write(origin.path().join("contest/task.md"), content).unwrap();
+        let repo = Repository::init(origin.path()).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("contest/task.md")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("bot", "bot@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        origin
+    }
+
+    async fn snapshot(cache: &RepoCache, origin: &WorkDir) -> String {
+        let url = origin.path().to_str().unwrap().to_string();
+        let host_check = Arc::new(HostKeyCheck::new(GitRemote::parse(&url).unwrap(), None));
+        let (job, _) = cache
+            .snapshot(
+                GuildId(42),
+                url,
+                Credentials::Anonymous,
+                None,
+                "contest".to_string(),
+                host_check,
+            )
+            .await
+            .unwrap();
+        fs::read_to_string(job.path().join("contest/task.md")).unwrap()
+    }
+
+    fn name_of(origin: &WorkDir) -> String {
+        clone_name(GuildId(42), origin.path().to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_clone() {
+        let root = WorkDir::new().unwrap();
+        let cache = RepoCache::in_dir(root.path().join("repos"), 2).unwrap();
+        let (a, b, c) = (origin("a"), origin("b"), origin("c"));
+        assert_eq!(snapshot(&cache, &a).await, "a");
+        assert_eq!(snapshot(&cache, &b).await, "b");
+        assert_eq!(snapshot(&cache, &a).await, "a");
+        assert_eq!(snapshot(&cache, &c).await, "c");
+        assert!(cache.is_cached(&name_of(&a)));
+        assert!(!cache.is_cached(&name_of(&b)));
+        assert!(!root.path().join("repos").join(name_of(&b)).exists());
+        assert!(cache.is_cached(&name_of(&c)));
+    }
+
+    #[tokio::test]
+    async fn clones_again_when_the_clone_is_corrupted() {
+        let root = WorkDir::new().unwrap();
+        let cache = RepoCache::in_dir(root.path().join("repos"), 2).unwrap();
+        let a = origin("a");
+        assert_eq!(snapshot(&cache, &a).await, "a");
+        let clone = root.path().join("repos").join(name_of(&a));
+        fs::remove_dir_all(clone.join(".git/objects")).unwrap();
+        fs::create_dir(clone.join(".git/objects")).unwrap();
+        assert_eq!(snapshot(&cache, &a).await, "a");
+    }
+
+    #[test]
+    fn tells_missing_directories_apart() {
+        let origin = origin("a");
+        let dest = WorkDir::new().unwrap();
+        for subdir in ["missing", "contest/task.md"] {
+            let extracted = extract_commit(origin.path(), None, subdir, &dest.path().join(subdir));
+            assert!(
+                matches!(extracted, Err(TaskPdfWriterBotError::MissingDirectory(_))),
+                "{}",
+                subdir
+            );
+        }
+        assert!(
+            extract_commit(origin.path(), None, "contest", &dest.path().join("contest")).is_ok()
+        );
+    }
+
+    #[test]
+    fn lists_remote_refs_without_fetching() {
+        let origin = WorkDir::new().unwrap();
+        let repo = Repository::init(origin.path()).unwrap();
+        let signature = git2::Signature::now("bot", "bot@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        repo.tag_lightweight("v1", &repo.find_object(commit, None).unwrap(), false)
+            .unwrap();
+        let url = origin.path().to_str().unwrap();
+        let host_check = Arc::new(HostKeyCheck::new(GitRemote::parse(url).unwrap(), None));
+        let refs = ls_remote(url, &Credentials::Anonymous, &host_check).unwrap();
+        let head = repo.head().unwrap().name().unwrap().to_string();
+        assert_eq!(refs.default_branch.as_deref(), Some(head.as_str()));
+        assert_eq!(refs.branches, vec![head.trim_start_matches("refs/heads/")]);
+        assert_eq!(refs.tags, vec!["v1"]);
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use git2::Oid;
+use serenity::async_trait;
+use serenity::model::prelude::{ChannelId, GuildId, RoleId};
+
+use super::{ConfigChange, ContestStore, SecretSlot, StoredSecret};
+use crate::config_history::{ConfigVersion, HistoryEntry, REVOKED};
+use crate::contests::{ContestRef, ContestSummary};
+use crate::git_remote::GitRemote;
+use crate::known_hosts::{HostKey, KnownHost};
+use crate::permissions::Capability;
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+#[derive(Default)]
+struct Data {
+    /// By guild and contest name.
+    contests: BTreeMap<(GuildId, String), (ConfigVersion, Option<ChannelId>)>,
+    active: BTreeMap<GuildId, String>,
+    /// Oldest first.
+    history: Vec<(ContestRef, HistoryEntry)>,
+    known_hosts: BTreeMap<(GuildId, String, u16), KnownHost>,
+    permissions: BTreeSet<(GuildId, &'static str, RoleId)>,
+    /// By key, with when it was last used.
+    pdfs: BTreeMap<String, Instant>,
+}
+
+/// A store that only lives in memory, for tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Data>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+fn key(contest: &ContestRef) -> (GuildId, String) {
+    (contest.guild_id, contest.name.clone())
+}
+
+fn host(guild_id: GuildId, remote: &GitRemote) -> (GuildId, String, u16) {
+    (guild_id, remote.host.clone(), remote.port_or_default())
+}
+
+/// The secret in `slot` of a version, with its master key version.
+fn secret_of(
+    version: &mut ConfigVersion,
+    slot: SecretSlot,
+) -> (&mut Option<Vec<u8>>, &mut Option<i32>) {
+    match slot {
+        SecretSlot::ContestKey | SecretSlot::HistoryKey => {
+            (&mut version.private_key, &mut version.private_key_version)
+        }
+        SecretSlot::ContestToken | SecretSlot::HistoryToken => {
+            (&mut version.https_token, &mut version.https_token_version)
+        }
+    }
+}
+
+impl Data {
+    /// Every stored version with its guild, as the slots of `key` and `token`.
+    fn versions_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (GuildId, &mut ConfigVersion, SecretSlot, SecretSlot)> {
+        let contests = self.contests.iter_mut().map(|((guild_id, _), (v, _))| {
+            (
+                *guild_id,
+                v,
+                SecretSlot::ContestKey,
+                SecretSlot::ContestToken,
+            )
+        });
+        let history = self.history.iter_mut().map(|(contest, entry)| {
+            (
+                contest.guild_id,
+                &mut entry.version,
+                SecretSlot::HistoryKey,
+                SecretSlot::HistoryToken,
+            )
+        });
+        contests.chain(history)
+    }
+}
+
+#[async_trait]
+impl ContestStore for MemoryStore {
+    async fn migrate(&self) -> Result<(), TaskPdfWriterBotError> {
+        Ok(())
+    }
+
+    async fn contest(
+        &self,
+        contest: &ContestRef,
+    ) -> Result<Option<ConfigVersion>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.contests.get(&key(contest)).map(|(v, _)| v.clone()))
+    }
+
+    async fn change_contest(
+        &self,
+        change: &ConfigChange<'_>,
+    ) -> Result<bool, TaskPdfWriterBotError> {
+        let contest = change.contest;
+        if let Some(version) = change.version {
+            if version.git_remote_url.is_none() || version.contest_rel_path.is_none() {
+                Err(MyError::new("a contest needs a URL and a reldir"))?;
+            }
+        }
+        let mut data = self.data.lock().unwrap();
+        let existed = match change.version {
+            Some(version) if change.create => {
+                let taken = data.contests.iter().any(|((g, name), (_, c))| {
+                    *g == contest.guild_id
+                        && (*name == contest.name || (c.is_some() && *c == change.channel_id))
+                });
+                if taken {
+                    return Ok(false);
+                }
+                data.contests
+                    .insert(key(contest), (version.clone(), change.channel_id));
+                true
+            }
+            Some(version) => {
+                let channel_id = data.contests.get(&key(contest)).and_then(|(_, c)| *c);
+                data.contests
+                    .insert(key(contest), (version.clone(), channel_id));
+                true
+            }
+            None => {
+                let existed = data.contests.remove(&key(contest)).is_some();
+                if data.active.get(&contest.guild_id) == Some(&contest.name) {
+                    data.active.remove(&contest.guild_id);
+                }
+                if !data.contests.keys().any(|(g, _)| *g == contest.guild_id) {
+                    data.known_hosts
+                        .retain(|(g, _, _), _| *g != contest.guild_id);
+                }
+                existed
+            }
+        };
+        if let Some(author) = &change.author {
+            let entry = HistoryEntry {
+                id: data.history.len() as i64 + 1,
+                changed_by_name: author.changed_by_name.to_string(),
+                changed_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as i64),
+                action: author.action.to_string(),
+                version: change.version.cloned().unwrap_or_default(),
+            };
+            data.history.push((contest.clone(), entry));
+        }
+        for revoked in &change.revoked {
+            for (_, entry) in data.history.iter_mut().filter(|(c, _)| c == contest) {
+                let (secret, _) = secret_of(&mut entry.version, revoked.slot);
+                if secret.as_ref() == Some(&revoked.sealed) {
+                    *secret = Some(REVOKED.to_vec());
+                }
+            }
+        }
+        Ok(existed)
+    }
+
+    async fn contests(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Vec<ContestSummary>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .contests
+            .iter()
+            .filter(|((g, _), _)| *g == guild_id)
+            .map(|((_, name), (version, channel_id))| ContestSummary {
+                name: name.clone(),
+                channel_id: channel_id.map(|c| c.to_string()),
+                git_remote_url: version.git_remote_url.clone().unwrap_or_default(),
+                contest_rel_path: version.contest_rel_path.clone().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn bind_contest(
+        &self,
+        contest: &ContestRef,
+        channel_id: Option<ChannelId>,
+    ) -> Result<bool, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        match data.contests.get_mut(&key(contest)) {
+            Some((_, bound)) => {
+                *bound = channel_id;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn active_contest(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Option<String>, TaskPdfWriterBotError> {
+        Ok(self.data.lock().unwrap().active.get(&guild_id).cloned())
+    }
+
+    async fn set_active_contest(&self, contest: &ContestRef) -> Result<(), TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        data.active.insert(contest.guild_id, contest.name.clone());
+        Ok(())
+    }
+
+    async fn secrets(&self) -> Result<Vec<StoredSecret>, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        let mut secrets = Vec::new();
+        for (guild_id, version, key_slot, token_slot) in data.versions_mut() {
+            for slot in [key_slot, token_slot] {
+                let (sealed, sealed_with) = secret_of(version, slot);
+                if let Some(sealed) = sealed.as_ref().filter(|s| s.as_slice() != REVOKED) {
+                    let secret = StoredSecret {
+                        slot,
+                        guild_id: guild_id.to_string(),
+                        sealed: sealed.clone(),
+                        version: *sealed_with,
+                    };
+                    if !secrets.contains(&secret) {
+                        secrets.push(secret);
+                    }
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    async fn replace_secret(
+        &self,
+        old: &StoredSecret,
+        sealed: &[u8],
+        version: i32,
+    ) -> Result<u64, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        let mut count = 0;
+        for (guild_id, stored, key_slot, token_slot) in data.versions_mut() {
+            if guild_id.to_string() != old.guild_id {
+                continue;
+            }
+            for slot in [key_slot, token_slot] {
+                if !old.slot.copies().contains(&slot) {
+                    continue;
+                }
+                let (secret, sealed_with) = secret_of(stored, slot);
+                if secret.as_deref() == Some(old.sealed.as_slice()) {
+                    *secret = Some(sealed.to_vec());
+                    *sealed_with = Some(version);
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    async fn history(
+        &self,
+        contest: &ContestRef,
+        limit: i64,
+    ) -> Result<Vec<HistoryEntry>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .history
+            .iter()
+            .rev()
+            .filter(|(c, _)| c == contest)
+            .take(limit.max(0) as usize)
+            .map(|(_, entry)| entry.clone())
+            .collect())
+    }
+
+    async fn history_entry(
+        &self,
+        contest: &ContestRef,
+        id: i64,
+    ) -> Result<Option<HistoryEntry>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .history
+            .iter()
+            .find(|(c, entry)| c == contest && entry.id == id)
+            .map(|(_, entry)| entry.clone()))
+    }
+
+    async fn known_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+    ) -> Result<Option<KnownHost>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.known_hosts.get(&host(guild_id, remote)).cloned())
+    }
+
+    async fn remember_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+        key: &HostKey,
+        pinned: bool,
+    ) -> Result<(), TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        let host = host(guild_id, remote);
+        if pinned || !data.known_hosts.contains_key(&host) {
+            let key = KnownHost {
+                key: key.clone(),
+                pinned,
+            };
+            data.known_hosts.insert(host, key);
+        }
+        Ok(())
+    }
+
+    async fn forget_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+    ) -> Result<bool, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.known_hosts.remove(&host(guild_id, remote)).is_some())
+    }
+
+    async fn granted_roles(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+    ) -> Result<Vec<RoleId>, TaskPdfWriterBotError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .permissions
+            .iter()
+            .filter(|(g, c, _)| *g == guild_id && *c == capability.name())
+            .map(|(_, _, role_id)| *role_id)
+            .collect())
+    }
+
+    async fn grant(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+        role_id: RoleId,
+    ) -> Result<bool, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .permissions
+            .insert((guild_id, capability.name(), role_id)))
+    }
+
+    async fn revoke(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+        role_id: RoleId,
+    ) -> Result<bool, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .permissions
+            .remove(&(guild_id, capability.name(), role_id)))
+    }
+
+    async fn touch_pdf(&self, key: &str) -> Result<bool, TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        match data.pdfs.get_mut(key) {
+            Some(used) => {
+                *used = Instant::now();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn index_pdf(
+        &self,
+        key: &str,
+        _guild_id: GuildId,
+        _task_name: &str,
+        _commit: Oid,
+        _renderer: &str,
+    ) -> Result<(), TaskPdfWriterBotError> {
+        let mut data = self.data.lock().unwrap();
+        data.pdfs.insert(key.to_string(), Instant::now());
+        Ok(())
+    }
+
+    async fn forget_pdf(&self, key: &str) -> Result<(), TaskPdfWriterBotError> {
+        self.data.lock().unwrap().pdfs.remove(key);
+        Ok(())
+    }
+
+    async fn expire_pdfs(&self, unused_for: i64) -> Result<Vec<String>, TaskPdfWriterBotError> {
+        let unused_for = Duration::from_secs(unused_for.max(0) as u64);
+        let mut data = self.data.lock().unwrap();
+        let expired: Vec<String> = data
+            .pdfs
+            .iter()
+            .filter(|(_, used)| used.elapsed() >= unused_for)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            data.pdfs.remove(key);
+        }
+        Ok(expired)
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+
+use git2::Oid;
+use serenity::async_trait;
+use serenity::model::prelude::{ChannelId, GuildId, RoleId};
+use shuttle_secrets::SecretStore;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::PgPool;
+
+use crate::config_history::{ConfigVersion, HistoryEntry};
+use crate::contests::{ContestRef, ContestSummary};
+use crate::git_remote::GitRemote;
+use crate::known_hosts::{HostKey, KnownHost};
+use crate::permissions::Capability;
+use crate::traits::{MyError, TaskPdfWriterBotError};
+
+#[cfg(test)]
+pub mod memory;
+pub mod postgres;
+pub mod sql;
+pub mod sqlite;
+
+/// Where the SQLite database is kept when `SQLITE_PATH` is not set.
+const DEFAULT_SQLITE_PATH: &str = "database.sqlite";
+
+/// Where a sealed secret is stored. The configuration history keeps the
+/// secrets of earlier versions, so they are rotated too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretSlot {
+    ContestKey,
+    ContestToken,
+    HistoryKey,
+    HistoryToken,
+}
+
+impl SecretSlot {
+    pub const ALL: [SecretSlot; 4] = [
+        SecretSlot::ContestKey,
+        SecretSlot::ContestToken,
+        SecretSlot::HistoryKey,
+        SecretSlot::HistoryToken,
+    ];
+
+    /// The slots holding the same kind of secret, in the contests and in
+    /// their history, which share the ciphertext of a secret they both hold.
+    pub fn copies(self) -> [SecretSlot; 2] {
+        match self {
+            SecretSlot::ContestKey | SecretSlot::HistoryKey => {
+                [SecretSlot::ContestKey, SecretSlot::HistoryKey]
+            }
+            SecretSlot::ContestToken | SecretSlot::HistoryToken => {
+                [SecretSlot::ContestToken, SecretSlot::HistoryToken]
+            }
+        }
+    }
+}
+
+/// A secret as stored, sealed with master key `version` (`None` for a secret
+/// stored before encryption at rest).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSecret {
+    pub slot: SecretSlot,
+    pub guild_id: String,
+    pub sealed: Vec<u8>,
+    pub version: Option<i32>,
+}
+
+/// A secret to revoke in the history of a contest. Stores revoke the copies
+/// sealed exactly like `sealed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevokedSecret {
+    pub slot: SecretSlot,
+    pub sealed: Vec<u8>,
+    /// The master key version it is sealed with.
+    pub version: Option<i32>,
+}
+
+/// A change to the configuration of a contest, applied by
+/// [`ContestStore::change_contest`].
+pub struct ConfigChange<'a> {
+    pub contest: &'a ContestRef,
+    /// The new configuration, or `None` to delete the contest.
+    pub version: Option<&'a ConfigVersion>,
+    /// Who made the change, to record the new configuration in the history.
+    /// Not recorded when `None`.
+    pub author: Option<ChangeAuthor<'a>>,
+    /// Sealed secrets to replace with [`crate::config_history::REVOKED`] in
+    /// the history of the contest, each in its `History*` slot.
+    pub revoked: Vec<RevokedSecret>,
+    /// Creates the contest instead of replacing it. Nothing changes and
+    /// [`ContestStore::change_contest`] returns `false` if the contest exists
+    /// or another contest is bound to `channel_id`.
+    pub create: bool,
+    /// The channel or category a created contest is bound to.
+    pub channel_id: Option<ChannelId>,
+}
+
+impl<'a> ConfigChange<'a> {
+    /// A change that is neither recorded nor revokes anything.
+    pub fn new(contest: &'a ContestRef, version: Option<&'a ConfigVersion>) -> Self {
+        ConfigChange {
+            contest,
+            version,
+            author: None,
+            revoked: Vec::new(),
+            create: false,
+            channel_id: None,
+        }
+    }
+}
+
+/// Who changed a configuration, and with which `/config` subcommand.
+#[derive(Clone, Copy)]
+pub struct ChangeAuthor<'a> {
+    pub changed_by: &'a str,
+    pub changed_by_name: &'a str,
+    pub action: &'a str,
+}
+
+/// Everything the bot keeps between commands: the contests and their
+/// secrets, the configuration history, trusted host keys, role permissions
+/// and the index of the PDF cache. Command logic only talks to this trait, so
+/// it runs the same against Postgres and against [`memory::MemoryStore`] in
+/// tests.
+#[async_trait]
+pub trait ContestStore: Send + Sync {
+    /// Brings the schema up to date. Run once at startup.
+    async fn migrate(&self) -> Result<(), TaskPdfWriterBotError>;
+
+    /// The configuration of a contest, `None` if it was never set up.
+    async fn contest(
+        &self,
+        contest: &ContestRef,
+    ) -> Result<Option<ConfigVersion>, TaskPdfWriterBotError>;
+    /// Applies `change` in one transaction. Deleting a contest also unsets it as
+    /// the active contest and, once no contest of the guild is left, forgets
+    /// the guild's host keys. Returns `false` if it deletes a contest that
+    /// doesn't exist or doesn't create one, see [`ConfigChange::create`].
+    async fn change_contest(
+        &self,
+        change: &ConfigChange<'_>,
+    ) -> Result<bool, TaskPdfWriterBotError>;
+    /// Creates or replaces the configuration of a contest, secrets included,
+    /// without recording it. The URL and the reldir must be set.
+    #[cfg(test)]
+    async fn save_contest(
+        &self,
+        contest: &ContestRef,
+        version: &ConfigVersion,
+    ) -> Result<(), TaskPdfWriterBotError> {
+        self.change_contest(&ConfigChange::new(contest, Some(version)))
+            .await?;
+        Ok(())
+    }
+    /// Every contest of a guild, by name.
+    async fn contests(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Vec<ContestSummary>, TaskPdfWriterBotError>;
+    /// Binds a contest to a channel or category, or unbinds it with `None`.
+    /// Returns whether the contest exists.
+    async fn bind_contest(
+        &self,
+        contest: &ContestRef,
+        channel_id: Option<ChannelId>,
+    ) -> Result<bool, TaskPdfWriterBotError>;
+    async fn active_contest(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Option<String>, TaskPdfWriterBotError>;
+    async fn set_active_contest(&self, contest: &ContestRef) -> Result<(), TaskPdfWriterBotError>;
+
+    /// Every stored secret, in contests and in their history, except the
+    /// revoked ones.
+    async fn secrets(&self) -> Result<Vec<StoredSecret>, TaskPdfWriterBotError>;
+    /// Replaces every copy of `old`, in the contests and in their history, with
+    /// a secret sealed with master key `version`, in one transaction. Copies
+    /// that changed in the meantime are left alone. Returns how many copies
+    /// were replaced.
+    async fn replace_secret(
+        &self,
+        old: &StoredSecret,
+        sealed: &[u8],
+        version: i32,
+    ) -> Result<u64, TaskPdfWriterBotError>;
+
+    /// The `limit` latest versions of the contest's configuration, newest first.
+    async fn history(
+        &self,
+        contest: &ContestRef,
+        limit: i64,
+    ) -> Result<Vec<HistoryEntry>, TaskPdfWriterBotError>;
+    async fn history_entry(
+        &self,
+        contest: &ContestRef,
+        id: i64,
+    ) -> Result<Option<HistoryEntry>, TaskPdfWriterBotError>;
+
+    async fn known_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+    ) -> Result<Option<KnownHost>, TaskPdfWriterBotError>;
+    /// Stores the host key of `remote` for the guild. A pinned key replaces any
+    /// stored one, while a key seen on first use never does.
+    async fn remember_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+        key: &HostKey,
+        pinned: bool,
+    ) -> Result<(), TaskPdfWriterBotError>;
+    async fn forget_host(
+        &self,
+        guild_id: GuildId,
+        remote: &GitRemote,
+    ) -> Result<bool, TaskPdfWriterBotError>;
+
+    async fn granted_roles(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+    ) -> Result<Vec<RoleId>, TaskPdfWriterBotError>;
+    /// Returns whether the role didn't have the capability yet.
+    async fn grant(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+        role_id: RoleId,
+    ) -> Result<bool, TaskPdfWriterBotError>;
+    /// Returns whether the role had the capability.
+    async fn revoke(
+        &self,
+        guild_id: GuildId,
+        capability: Capability,
+        role_id: RoleId,
+    ) -> Result<bool, TaskPdfWriterBotError>;
+
+    /// Marks a cached PDF as used. Returns whether it is indexed.
+    async fn touch_pdf(&self, key: &str) -> Result<bool, TaskPdfWriterBotError>;
+    async fn index_pdf(
+        &self,
+        key: &str,
+        guild_id: GuildId,
+        task_name: &str,
+        commit: Oid,
+        renderer: &str,
+    ) -> Result<(), TaskPdfWriterBotError>;
+    async fn forget_pdf(&self, key: &str) -> Result<(), TaskPdfWriterBotError>;
+    /// Forgets the PDFs not used for `unused_for` seconds and returns their
+    /// keys, so their files can be deleted.
+    async fn expire_pdfs(&self, unused_for: i64) -> Result<Vec<String>, TaskPdfWriterBotError>;
+}
+
+/// The store chosen by the `DATABASE` secret: `postgres` (the default) uses
+/// the database provisioned by Shuttle, or the one at `DATABASE_URL` when
+/// there is none, and `sqlite` a file at `SQLITE_PATH`, created if missing.
+pub async fn store_from_secrets(
+    secret_store: &SecretStore,
+    provisioned: Option<PgPool>,
+) -> Result<Arc<dyn ContestStore>, TaskPdfWriterBotError> {
+    let kind = secret_store
+        .get("DATABASE")
+        .unwrap_or_else(|| "postgres".to_string());
+    match kind.as_str() {
+        "postgres" => {
+            let pool = match provisioned {
+                Some(pool) => pool,
+                None => {
+                    let url = match secret_store.get("DATABASE_URL") {
+                        Some(url) => url,
+                        None => Err(MyError::new("'DATABASE_URL' was not found"))?,
+                    };
+                    PgPoolOptions::new()
+                        .max_connections(5)
+                        .connect(&url)
+                        .await?
+                }
+            };
+            Ok(Arc::new(postgres::PgStore::new(pool)))
+        }
+        "sqlite" => {
+            let path = secret_store
+                .get("SQLITE_PATH")
+                .unwrap_or_else(|| DEFAULT_SQLITE_PATH.to_string());
+            let pool = SqlitePoolOptions::new()
+                .max_connections(5)
+                .connect_with(
+                    SqliteConnectOptions::new()
+                        .filename(path)
+                        .create_if_missing(true)
+                        .journal_mode(SqliteJournalMode::Wal),
+                )
+                .await?;
+            Ok(Arc::new(sqlite::SqliteStore::new(pool)))
+        }
+        _ => Err(MyError::new(&format!("unknown database '{}'", kind)))?,
+    }
+}
@@ -0,0 +1,17 @@
+use sqlx::PgPool;
+
+use super::sql::{sql_store, POSTGRES};
+use crate::migrations;
+
+/// The store of a deployment, in Postgres.
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> Self {
+        PgStore { pool }
+    }
+}
+
+sql_store!(PgStore, POSTGRES, migrations::run);
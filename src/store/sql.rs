@@ -0,0 +1,526 @@
+//! The SQL shared by [`super::postgres::PgStore`] and
+//! [`super::sqlite::SqliteStore`]. Both databases take `$1`, `$2`, ...
+//! parameters, so the queries only differ in the expressions of a
+//! [`Dialect`].
+
+use sqlx::database::HasArguments;
+use sqlx::query::Query;
+use sqlx::{Database, Encode, Type};
+
+use super::SecretSlot;
+use crate::config_history::{ConfigVersion, COLUMNS};
+
+/// The expressions that are written differently in each database.
+pub struct Dialect {
+    /// The current time.
+    pub now: &'static str,
+    /// `changed_at` in seconds since the epoch.
+    pub changed_at: &'static str,
+    /// The time `$1` seconds ago.
+    pub seconds_ago: &'static str,
+}
+
+pub const POSTGRES: Dialect = Dialect {
+    now: "now()",
+    changed_at: "EXTRACT(EPOCH FROM changed_at)::BIGINT",
+    seconds_ago: "now() - $1 * INTERVAL '1 second'",
+};
+
+pub const SQLITE: Dialect = Dialect {
+    now: "CURRENT_TIMESTAMP",
+    changed_at: "CAST(strftime('%s', changed_at) AS INTEGER)",
+    seconds_ago: "datetime('now', '-' || $1 || ' seconds')",
+};
+
+pub fn bind_version<'q, DB: Database>(
+    query: Query<'q, DB, <DB as HasArguments<'q>>::Arguments>,
+    version: &'q ConfigVersion,
+) -> Query<'q, DB, <DB as HasArguments<'q>>::Arguments>
+where
+    Option<String>: Encode<'q, DB> + Type<DB>,
+    Option<Vec<u8>>: Encode<'q, DB> + Type<DB>,
+    Option<i32>: Encode<'q, DB> + Type<DB>,
+{
+    query
+        .bind(&version.git_remote_url)
+        .bind(&version.contest_rel_path)
+        .bind(&version.private_key)
+        .bind(version.private_key_version)
+        .bind(&version.https_username)
+        .bind(&version.https_token)
+        .bind(version.https_token_version)
+        .bind(&version.renderer_url)
+        .bind(&version.default_branch)
+        .bind(&version.default_lang)
+}
+
+/// `$first, $first + 1, ...`, one per configuration column.
+pub fn placeholders(first: usize) -> String {
+    (first..first + COLUMNS.len())
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn select_entries(dialect: &Dialect, condition: &str) -> String {
+    format!(
+        "SELECT id, changed_by_name, {} AS changed_at, action, {} FROM contest_config_history WHERE {}",
+        dialect.changed_at,
+        COLUMNS.join(", "),
+        condition
+    )
+}
+
+pub fn secret_column(slot: SecretSlot) -> (&'static str, &'static str) {
+    match slot {
+        SecretSlot::ContestKey => ("contests", "private_key"),
+        SecretSlot::ContestToken => ("contests", "https_token"),
+        SecretSlot::HistoryKey => ("contest_config_history", "private_key"),
+        SecretSlot::HistoryToken => ("contest_config_history", "https_token"),
+    }
+}
+
+/// Implements [`super::ContestStore`] for `$store`, a struct with a `pool`
+/// field, with the queries above in `$dialect`. `$migrate` brings the
+/// schema of the pool up to date.
+macro_rules! sql_store {
+    ($store:ty, $dialect:expr, $migrate:path) => {
+        const _: () = {
+            use git2::Oid;
+            use serenity::async_trait;
+            use serenity::model::prelude::{ChannelId, GuildId, RoleId};
+
+            use $crate::config_history::{ConfigVersion, HistoryEntry, COLUMNS, REVOKED};
+            use $crate::contests::{ContestRef, ContestSummary};
+            use $crate::git_remote::GitRemote;
+            use $crate::known_hosts::{HostKey, KnownHost};
+            use $crate::permissions::Capability;
+            use $crate::store::sql::{bind_version, placeholders, secret_column, select_entries};
+            use $crate::store::{ConfigChange, ContestStore, SecretSlot, StoredSecret};
+            use $crate::traits::TaskPdfWriterBotError;
+
+            #[async_trait]
+            impl ContestStore for $store {
+                async fn migrate(&self) -> Result<(), TaskPdfWriterBotError> {
+                    $migrate(&self.pool).await
+                }
+
+                async fn contest(
+                    &self,
+                    contest: &ContestRef,
+                ) -> Result<Option<ConfigVersion>, TaskPdfWriterBotError> {
+                    Ok(sqlx::query_as(&format!(
+                        "SELECT {} FROM contests WHERE guild_id = $1 AND name = $2",
+                        COLUMNS.join(", ")
+                    ))
+                    .bind(contest.guild_id.to_string())
+                    .bind(&contest.name)
+                    .fetch_optional(&self.pool)
+                    .await?)
+                }
+
+                async fn change_contest(
+                    &self,
+                    change: &ConfigChange<'_>,
+                ) -> Result<bool, TaskPdfWriterBotError> {
+                    let contest = change.contest;
+                    let guild_id = contest.guild_id.to_string();
+                    let mut transaction = self.pool.begin().await?;
+                    let existed = match change.version {
+                        Some(version) if change.create => {
+                            let sql = format!(
+                                "INSERT INTO contests (guild_id, name, channel_id, {}) SELECT $1, $2, $3, {} WHERE NOT EXISTS (SELECT 1 FROM contests WHERE guild_id = $1 AND (name = $2 OR channel_id = $3)) ON CONFLICT (guild_id, name) DO NOTHING",
+                                COLUMNS.join(", "),
+                                placeholders(4)
+                            );
+                            let query = sqlx::query(&sql)
+                                .bind(&guild_id)
+                                .bind(&contest.name)
+                                .bind(change.channel_id.map(|c| c.to_string()));
+                            let result =
+                                bind_version(query, version).execute(&mut transaction).await?;
+                            if result.rows_affected() == 0 {
+                                return Ok(false);
+                            }
+                            true
+                        }
+                        Some(version) => {
+                            let updates: Vec<String> = COLUMNS
+                                .iter()
+                                .map(|c| format!("{0} = EXCLUDED.{0}", c))
+                                .collect();
+                            let sql = format!(
+                                "INSERT INTO contests (guild_id, name, {}) VALUES ($1, $2, {}) ON CONFLICT (guild_id, name) DO UPDATE SET {}",
+                                COLUMNS.join(", "),
+                                placeholders(3),
+                                updates.join(", ")
+                            );
+                            let query = sqlx::query(&sql).bind(&guild_id).bind(&contest.name);
+                            bind_version(query, version).execute(&mut transaction).await?;
+                            true
+                        }
+                        None => {
+                            let result = sqlx::query(
+                                "DELETE FROM contests WHERE guild_id = $1 AND name = $2",
+                            )
+                            .bind(&guild_id)
+                            .bind(&contest.name)
+                            .execute(&mut transaction)
+                            .await?;
+                            sqlx::query(
+                                "DELETE FROM active_contests WHERE guild_id = $1 AND name = $2",
+                            )
+                            .bind(&guild_id)
+                            .bind(&contest.name)
+                            .execute(&mut transaction)
+                            .await?;
+                            sqlx::query(
+                                "DELETE FROM known_hosts WHERE guild_id = $1 AND NOT EXISTS (SELECT 1 FROM contests WHERE guild_id = $1)",
+                            )
+                            .bind(&guild_id)
+                            .execute(&mut transaction)
+                            .await?;
+                            result.rows_affected() > 0
+                        }
+                    };
+                    if let Some(author) = &change.author {
+                        let deleted = ConfigVersion::default();
+                        let sql = format!(
+                            "INSERT INTO contest_config_history (guild_id, contest_name, changed_by, changed_by_name, action, {}) VALUES ($1, $2, $3, $4, $5, {})",
+                            COLUMNS.join(", "),
+                            placeholders(6)
+                        );
+                        let query = sqlx::query(&sql)
+                            .bind(&guild_id)
+                            .bind(&contest.name)
+                            .bind(author.changed_by)
+                            .bind(author.changed_by_name)
+                            .bind(author.action);
+                        bind_version(query, change.version.unwrap_or(&deleted))
+                            .execute(&mut transaction)
+                            .await?;
+                    }
+                    for revoked in &change.revoked {
+                        let (table, column) = secret_column(revoked.slot);
+                        sqlx::query(&format!(
+                            "UPDATE {0} SET {1} = $1 WHERE guild_id = $2 AND contest_name = $3 AND {1} = $4",
+                            table, column
+                        ))
+                        .bind(REVOKED)
+                        .bind(&guild_id)
+                        .bind(&contest.name)
+                        .bind(&revoked.sealed)
+                        .execute(&mut transaction)
+                        .await?;
+                    }
+                    transaction.commit().await?;
+                    Ok(existed)
+                }
+
+                async fn contests(
+                    &self,
+                    guild_id: GuildId,
+                ) -> Result<Vec<ContestSummary>, TaskPdfWriterBotError> {
+                    Ok(sqlx::query_as(
+                        "SELECT name, channel_id, git_remote_url, contest_rel_path FROM contests WHERE guild_id = $1 ORDER BY name",
+                    )
+                    .bind(guild_id.to_string())
+                    .fetch_all(&self.pool)
+                    .await?)
+                }
+
+                async fn bind_contest(
+                    &self,
+                    contest: &ContestRef,
+                    channel_id: Option<ChannelId>,
+                ) -> Result<bool, TaskPdfWriterBotError> {
+                    let result = sqlx::query(
+                        "UPDATE contests SET channel_id = $1 WHERE guild_id = $2 AND name = $3",
+                    )
+                    .bind(channel_id.map(|c| c.to_string()))
+                    .bind(contest.guild_id.to_string())
+                    .bind(&contest.name)
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(result.rows_affected() > 0)
+                }
+
+                async fn active_contest(
+                    &self,
+                    guild_id: GuildId,
+                ) -> Result<Option<String>, TaskPdfWriterBotError> {
+                    let active: Option<(String,)> =
+                        sqlx::query_as("SELECT name FROM active_contests WHERE guild_id = $1")
+                            .bind(guild_id.to_string())
+                            .fetch_optional(&self.pool)
+                            .await?;
+                    Ok(active.map(|(name,)| name))
+                }
+
+                async fn set_active_contest(
+                    &self,
+                    contest: &ContestRef,
+                ) -> Result<(), TaskPdfWriterBotError> {
+                    sqlx::query(
+                        "INSERT INTO active_contests (guild_id, name) VALUES ($1, $2) ON CONFLICT (guild_id) DO UPDATE SET name = EXCLUDED.name",
+                    )
+                    .bind(contest.guild_id.to_string())
+                    .bind(&contest.name)
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(())
+                }
+
+                async fn secrets(&self) -> Result<Vec<StoredSecret>, TaskPdfWriterBotError> {
+                    let mut secrets = Vec::new();
+                    for slot in SecretSlot::ALL {
+                        let (table, column) = secret_column(slot);
+                        let rows: Vec<(String, Vec<u8>, Option<i32>)> = sqlx::query_as(&format!(
+                            "SELECT DISTINCT guild_id, {1}, {1}_version FROM {0} WHERE {1} IS NOT NULL AND length({1}) > 0",
+                            table, column
+                        ))
+                        .fetch_all(&self.pool)
+                        .await?;
+                        secrets.extend(rows.into_iter().map(|(guild_id, sealed, version)| {
+                            StoredSecret {
+                                slot,
+                                guild_id,
+                                sealed,
+                                version,
+                            }
+                        }));
+                    }
+                    Ok(secrets)
+                }
+
+                async fn replace_secret(
+                    &self,
+                    old: &StoredSecret,
+                    sealed: &[u8],
+                    version: i32,
+                ) -> Result<u64, TaskPdfWriterBotError> {
+                    let mut transaction = self.pool.begin().await?;
+                    let mut count = 0;
+                    for slot in old.slot.copies() {
+                        let (table, column) = secret_column(slot);
+                        let result = sqlx::query(&format!(
+                            "UPDATE {0} SET {1} = $1, {1}_version = $2 WHERE guild_id = $3 AND {1} = $4",
+                            table, column
+                        ))
+                        .bind(sealed)
+                        .bind(version)
+                        .bind(&old.guild_id)
+                        .bind(&old.sealed)
+                        .execute(&mut transaction)
+                        .await?;
+                        count += result.rows_affected();
+                    }
+                    transaction.commit().await?;
+                    Ok(count)
+                }
+
+                async fn history(
+                    &self,
+                    contest: &ContestRef,
+                    limit: i64,
+                ) -> Result<Vec<HistoryEntry>, TaskPdfWriterBotError> {
+                    Ok(sqlx::query_as(&select_entries(
+                        &$dialect,
+                        "guild_id = $1 AND contest_name = $2 ORDER BY id DESC LIMIT $3",
+                    ))
+                    .bind(contest.guild_id.to_string())
+                    .bind(&contest.name)
+                    .bind(limit)
+                    .fetch_all(&self.pool)
+                    .await?)
+                }
+
+                async fn history_entry(
+                    &self,
+                    contest: &ContestRef,
+                    id: i64,
+                ) -> Result<Option<HistoryEntry>, TaskPdfWriterBotError> {
+                    Ok(sqlx::query_as(&select_entries(
+                        &$dialect,
+                        "guild_id = $1 AND contest_name = $2 AND id = $3",
+                    ))
+                    .bind(contest.guild_id.to_string())
+                    .bind(&contest.name)
+                    .bind(id)
+                    .fetch_optional(&self.pool)
+                    .await?)
+                }
+
+                async fn known_host(
+                    &self,
+                    guild_id: GuildId,
+                    remote: &GitRemote,
+                ) -> Result<Option<KnownHost>, TaskPdfWriterBotError> {
+                    Ok(sqlx::query_as(
+                        "SELECT key_type, public_key, fingerprint, pinned FROM known_hosts WHERE guild_id = $1 AND host = $2 AND port = $3",
+                    )
+                    .bind(guild_id.to_string())
+                    .bind(&remote.host)
+                    .bind(remote.port_or_default() as i32)
+                    .fetch_optional(&self.pool)
+                    .await?)
+                }
+
+                async fn remember_host(
+                    &self,
+                    guild_id: GuildId,
+                    remote: &GitRemote,
+                    key: &HostKey,
+                    pinned: bool,
+                ) -> Result<(), TaskPdfWriterBotError> {
+                    let on_conflict = match pinned {
+                        true => format!(
+                            "DO UPDATE SET key_type = EXCLUDED.key_type, public_key = EXCLUDED.public_key, fingerprint = EXCLUDED.fingerprint, pinned = TRUE, added_at = {}",
+                            $dialect.now
+                        ),
+                        false => "DO NOTHING".to_string(),
+                    };
+                    sqlx::query(&format!(
+                        "INSERT INTO known_hosts (guild_id, host, port, key_type, public_key, fingerprint, pinned) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (guild_id, host, port) {}",
+                        on_conflict
+                    ))
+                    .bind(guild_id.to_string())
+                    .bind(&remote.host)
+                    .bind(remote.port_or_default() as i32)
+                    .bind(&key.key_type)
+                    .bind(&key.public_key)
+                    .bind(&key.fingerprint)
+                    .bind(pinned)
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(())
+                }
+
+                async fn forget_host(
+                    &self,
+                    guild_id: GuildId,
+                    remote: &GitRemote,
+                ) -> Result<bool, TaskPdfWriterBotError> {
+                    let result = sqlx::query(
+                        "DELETE FROM known_hosts WHERE guild_id = $1 AND host = $2 AND port = $3",
+                    )
+                    .bind(guild_id.to_string())
+                    .bind(&remote.host)
+                    .bind(remote.port_or_default() as i32)
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(result.rows_affected() > 0)
+                }
+
+                async fn granted_roles(
+                    &self,
+                    guild_id: GuildId,
+                    capability: Capability,
+                ) -> Result<Vec<RoleId>, TaskPdfWriterBotError> {
+                    let rows: Vec<(String,)> = sqlx::query_as(
+                        "SELECT role_id FROM command_permissions WHERE guild_id = $1 AND capability = $2 ORDER BY role_id",
+                    )
+                    .bind(guild_id.to_string())
+                    .bind(capability.name())
+                    .fetch_all(&self.pool)
+                    .await?;
+                    Ok(rows
+                        .into_iter()
+                        .filter_map(|(role_id,)| role_id.parse().ok().map(RoleId))
+                        .collect())
+                }
+
+                async fn grant(
+                    &self,
+                    guild_id: GuildId,
+                    capability: Capability,
+                    role_id: RoleId,
+                ) -> Result<bool, TaskPdfWriterBotError> {
+                    let result = sqlx::query(
+                        "INSERT INTO command_permissions (guild_id, capability, role_id) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                    )
+                    .bind(guild_id.to_string())
+                    .bind(capability.name())
+                    .bind(role_id.to_string())
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(result.rows_affected() > 0)
+                }
+
+                async fn revoke(
+                    &self,
+                    guild_id: GuildId,
+                    capability: Capability,
+                    role_id: RoleId,
+                ) -> Result<bool, TaskPdfWriterBotError> {
+                    let result = sqlx::query(
+                        "DELETE FROM command_permissions WHERE guild_id = $1 AND capability = $2 AND role_id = $3",
+                    )
+                    .bind(guild_id.to_string())
+                    .bind(capability.name())
+                    .bind(role_id.to_string())
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(result.rows_affected() > 0)
+                }
+
+                async fn touch_pdf(&self, key: &str) -> Result<bool, TaskPdfWriterBotError> {
+                    let indexed: Option<(String,)> = sqlx::query_as(&format!(
+                        "UPDATE pdf_cache SET last_used_at = {} WHERE cache_key = $1 RETURNING cache_key",
+                        $dialect.now
+                    ))
+                    .bind(key)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                    Ok(indexed.is_some())
+                }
+
+                async fn index_pdf(
+                    &self,
+                    key: &str,
+                    guild_id: GuildId,
+                    task_name: &str,
+                    commit: Oid,
+                    renderer: &str,
+                ) -> Result<(), TaskPdfWriterBotError> {
+                    sqlx::query(&format!(
+                        "INSERT INTO pdf_cache (cache_key, guild_id, task_name, commit_sha, renderer) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (cache_key) DO UPDATE SET last_used_at = {}",
+                        $dialect.now
+                    ))
+                    .bind(key)
+                    .bind(guild_id.to_string())
+                    .bind(task_name)
+                    .bind(commit.to_string())
+                    .bind(renderer)
+                    .execute(&self.pool)
+                    .await?;
+                    Ok(())
+                }
+
+                async fn forget_pdf(&self, key: &str) -> Result<(), TaskPdfWriterBotError> {
+                    sqlx::query("DELETE FROM pdf_cache WHERE cache_key = $1")
+                        .bind(key)
+                        .execute(&self.pool)
+                        .await?;
+                    Ok(())
+                }
+
+                async fn expire_pdfs(
+                    &self,
+                    unused_for: i64,
+                ) -> Result<Vec<String>, TaskPdfWriterBotError> {
+                    let expired: Vec<(String,)> = sqlx::query_as(&format!(
+                        "DELETE FROM pdf_cache WHERE last_used_at <= {} RETURNING cache_key",
+                        $dialect.seconds_ago
+                    ))
+                    .bind(unused_for)
+                    .fetch_all(&self.pool)
+                    .await?;
+                    Ok(expired.into_iter().map(|(key,)| key).collect())
+                }
+            }
+        };
+    };
+}
+
+pub(crate) use sql_store;
@@ -0,0 +1,256 @@
+use sqlx::SqlitePool;
+
+use super::sql::{sql_store, SQLITE};
+use crate::migrations;
+
+/// The store of a self-hosted bot, in a SQLite file. It runs the queries of
+/// [`super::postgres::PgStore`] in the SQLite dialect.
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteStore { pool }
+    }
+}
+
+sql_store!(SqliteStore, SQLITE, migrations::run_sqlite);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_history::{ConfigVersion, REVOKED};
+    use crate::contests::ContestRef;
+    use crate::git_remote::GitRemote;
+    use crate::known_hosts::{HostKey, KnownHost};
+    use crate::migrations::SQLITE_MIGRATIONS;
+    use crate::store::{ChangeAuthor, ConfigChange, ContestStore, RevokedSecret, SecretSlot};
+    use git2::Oid;
+    use serenity::model::prelude::{ChannelId, GuildId};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::Executor;
+
+    /// Every connection to `sqlite::memory:` opens another database, so the
+    /// pool keeps a single one.
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    fn contest(name: &str) -> ContestRef {
+        ContestRef {
+            guild_id: GuildId(42),
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_contests_history_and_hosts() {
+        let store = SqliteStore::new(memory_pool().await);
+        store.migrate().await.unwrap();
+        store.migrate().await.unwrap();
+        let version = ConfigVersion {
+            git_remote_url: Some("ssh://git@example.com/repo.git".to_string()),
+            contest_rel_path: Some("contest".to_string()),
+            https_token: Some(b"sealed".to_vec()),
+            https_token_version: Some(1),
+            ..Default::default()
+        };
+        let (default, final_round) = (contest("default"), contest("final"));
+        store.save_contest(&default, &version).await.unwrap();
+        store.save_contest(&final_round, &version).await.unwrap();
+        assert!(store
+            .bind_contest(&final_round, Some(ChannelId(20)))
+            .await
+            .unwrap());
+        store.set_active_contest(&final_round).await.unwrap();
+        let listed = store.contests(GuildId(42)).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1].channel_id.as_deref(), Some("20"));
+
+        let author = ChangeAuthor {
+            changed_by: "7",
+            changed_by_name: "admin",
+            action: "set",
+        };
+        store
+            .change_contest(&ConfigChange {
+                author: Some(author),
+                ..ConfigChange::new(&default, Some(&version))
+            })
+            .await
+            .unwrap();
+        let history = store.history(&default, 5).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].changed_at > 0);
+        assert!(store.history(&final_round, 5).await.unwrap().is_empty());
+
+        // The token is stored in both contests and in the history.
+        let secrets = store.secrets().await.unwrap();
+        assert_eq!(secrets.len(), 2);
+        // Replacing the contests' copy replaces the history's too.
+        assert_eq!(
+            store
+                .replace_secret(&secrets[0], b"resealed", 2)
+                .await
+                .unwrap(),
+            3
+        );
+        let resealed = store.contest(&final_round).await.unwrap().unwrap();
+        assert_eq!(resealed.https_token.as_deref(), Some(&b"resealed"[..]));
+        assert_eq!(resealed.https_token_version, Some(2));
+        let history = store.history(&default, 5).await.unwrap();
+        assert_eq!(history[0].version.https_token, resealed.https_token);
+
+        // Revoking the token of a contest revokes it in its history too.
+        let revoked = ConfigVersion {
+            https_token: None,
+            https_token_version: None,
+            ..version.clone()
+        };
+        store
+            .change_contest(&ConfigChange {
+                author: Some(ChangeAuthor {
+                    action: "revoke-token",
+                    ..author
+                }),
+                revoked: vec![RevokedSecret {
+                    slot: SecretSlot::HistoryToken,
+                    sealed: b"resealed".to_vec(),
+                    version: Some(2),
+                }],
+                ..ConfigChange::new(&default, Some(&revoked))
+            })
+            .await
+            .unwrap();
+        let history = store.history(&default, 5).await.unwrap();
+        assert_eq!(history[0].version.https_token, None);
+        assert_eq!(history[1].version.https_token.as_deref(), Some(REVOKED));
+        assert_eq!(store.secrets().await.unwrap().len(), 1);
+
+        // Creating a contest refuses taken names and channels.
+        let mirror = contest("mirror");
+        let create = |contest, channel_id| ConfigChange {
+            author: Some(ChangeAuthor {
+                action: "contest create",
+                ..author
+            }),
+            create: true,
+            channel_id,
+            ..ConfigChange::new(contest, Some(&version))
+        };
+        assert!(!store.change_contest(&create(&default, None)).await.unwrap());
+        assert!(!store
+            .change_contest(&create(&mirror, Some(ChannelId(20))))
+            .await
+            .unwrap());
+        assert!(store.history(&mirror, 5).await.unwrap().is_empty());
+        assert!(store
+            .change_contest(&create(&mirror, Some(ChannelId(30))))
+            .await
+            .unwrap());
+        let history = store.history(&mirror, 5).await.unwrap();
+        assert_eq!(history[0].action, "contest create");
+        let listed = store.contests(GuildId(42)).await.unwrap();
+        assert_eq!(listed[2].channel_id.as_deref(), Some("30"));
+        assert!(store
+            .change_contest(&ConfigChange::new(&mirror, None))
+            .await
+            .unwrap());
+
+        let remote = GitRemote::parse("ssh://git@example.com/repo.git").unwrap();
+        let key = HostKey {
+            key_type: None,
+            public_key: None,
+            fingerprint: "SHA256:first".to_string(),
+        };
+        let pinned = HostKey {
+            fingerprint: "SHA256:pinned".to_string(),
+            ..key.clone()
+        };
+        store
+            .remember_host(GuildId(42), &remote, &key, false)
+            .await
+            .unwrap();
+        store
+            .remember_host(GuildId(42), &remote, &pinned, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.known_host(GuildId(42), &remote).await.unwrap(),
+            Some(KnownHost { key, pinned: false })
+        );
+        store
+            .remember_host(GuildId(42), &remote, &pinned, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.known_host(GuildId(42), &remote).await.unwrap(),
+            Some(KnownHost {
+                key: pinned,
+                pinned: true
+            })
+        );
+
+        // Host keys go with the last contest of the guild.
+        assert!(store
+            .change_contest(&ConfigChange::new(&final_round, None))
+            .await
+            .unwrap());
+        assert_eq!(store.active_contest(GuildId(42)).await.unwrap(), None);
+        assert!(store
+            .known_host(GuildId(42), &remote)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store
+            .change_contest(&ConfigChange::new(&default, None))
+            .await
+            .unwrap());
+        assert!(!store
+            .change_contest(&ConfigChange::new(&default, None))
+            .await
+            .unwrap());
+        assert!(store
+            .known_host(GuildId(42), &remote)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn expires_unused_pdfs() {
+        let store = SqliteStore::new(memory_pool().await);
+        store.migrate().await.unwrap();
+        store
+            .index_pdf("key", GuildId(42), "sum", Oid::zero(), "mock")
+            .await
+            .unwrap();
+        assert!(store.touch_pdf("key").await.unwrap());
+        assert!(store.expire_pdfs(3600).await.unwrap().is_empty());
+        assert_eq!(store.expire_pdfs(0).await.unwrap(), vec!["key"]);
+        assert!(!store.touch_pdf("key").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn named_contests_migration_keeps_existing_contests() {
+        let pool = memory_pool().await;
+        for migration in &SQLITE_MIGRATIONS[..6] {
+            pool.execute(migration.sql).await.unwrap();
+        }
+        sqlx::query(
+            "INSERT INTO contests (guild_id, git_remote_url, contest_rel_path) VALUES ('42', 'https://example.com/repo.git', 'contest')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.execute(SQLITE_MIGRATIONS[6].sql).await.unwrap();
+        let store = SqliteStore::new(pool);
+        let migrated = store.contest(&contest("default")).await.unwrap().unwrap();
+        assert_eq!(migrated.contest_rel_path.as_deref(), Some("contest"));
+    }
+}
@@ -2,14 +2,31 @@ use std::{fmt, io};
 
 use serenity::async_trait;
 use serenity::builder::CreateApplicationCommand;
-use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOptionValue,
+};
 use serenity::model::prelude::interaction::InteractionResponseType;
 use serenity::prelude::*;
 
+use crate::git_remote::RemotePolicy;
+use crate::keyring::Keyring;
+use crate::pdf_cache::PdfCache;
+use crate::permissions::Owners;
+use crate::renderer::PdfRenderer;
+use crate::repo_cache::RepoCache;
+use crate::store::ContestStore;
+
 pub struct CommandHandlerData<'a> {
     pub(super) command: &'a ApplicationCommandInteraction,
     pub(super) ctx: &'a Context,
-    pub(super) database: &'a sqlx::PgPool,
+    pub(super) store: &'a dyn ContestStore,
+    pub(super) renderer: &'a dyn PdfRenderer,
+    pub(super) repo_cache: &'a RepoCache,
+    pub(super) pdf_cache: &'a PdfCache,
+    pub(super) remote_policy: &'a RemotePolicy,
+    pub(super) keyring: &'a Keyring,
+    pub(super) owners: &'a Owners,
 }
 
 #[async_trait]
@@ -19,15 +36,59 @@ pub trait CommandHandle<'a> {
 }
 
 impl<'a> CommandHandlerData<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         command: &'a ApplicationCommandInteraction,
         ctx: &'a Context,
-        database: &'a sqlx::PgPool,
+        store: &'a dyn ContestStore,
+        renderer: &'a dyn PdfRenderer,
+        repo_cache: &'a RepoCache,
+        pdf_cache: &'a PdfCache,
+        remote_policy: &'a RemotePolicy,
+        keyring: &'a Keyring,
+        owners: &'a Owners,
     ) -> CommandHandlerData<'a> {
         CommandHandlerData {
             command,
             ctx,
-            database,
+            store,
+            renderer,
+            repo_cache,
+            pdf_cache,
+            remote_policy,
+            keyring,
+            owners,
+        }
+    }
+    /// The name of the subcommand that was invoked, if the command has any.
+    pub fn subcommand(&self) -> Option<&'a str> {
+        self.command
+            .data
+            .options
+            .first()
+            .filter(|option| option.kind == CommandOptionType::SubCommand)
+            .map(|option| option.name.as_str())
+    }
+    /// The value of the option called `name`, if it was given. Options of the
+    /// invoked subcommand are searched when there is one.
+    pub fn option(&self, name: &str) -> Option<&'a CommandDataOptionValue> {
+        let mut options = &self.command.data.options;
+        if let Some(subcommand) = options
+            .first()
+            .filter(|option| option.kind == CommandOptionType::SubCommand)
+        {
+            options = &subcommand.options;
+        }
+        options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| option.resolved.as_ref())
+    }
+    /// The value of the string option called `name`, if it was given.
+    pub fn string_option(&self, name: &str) -> Option<&'a str> {
+        match self.option(name) {
+            Some(CommandDataOptionValue::String(s)) => Some(s.as_str()),
+            _ => None,
         }
     }
 }
@@ -67,15 +128,19 @@ impl fmt::Display for MyError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum TaskPdfWriterBotError {
-    SerenityError(serenity::Error),
+    SerenityError(Box<serenity::Error>),
     InnerError(MyError),
     SqlxError(sqlx::Error),
     GitError(git2::Error),
     IOError(io::Error),
     ReqwestError(reqwest::Error),
     JsonError(serde_json::Error),
-    OpenSSHError(openssh::Error),
+    PdfError(lopdf::Error),
+    SshKeyError(ssh_key::Error),
+    /// The contest directory is missing from the fetched commit.
+    MissingDirectory(MyError),
 }
 impl std::error::Error for TaskPdfWriterBotError {}
 impl From<MyError> for TaskPdfWriterBotError {
@@ -85,7 +150,7 @@ impl From<MyError> for TaskPdfWriterBotError {
 }
 impl From<serenity::Error> for TaskPdfWriterBotError {
     fn from(err: serenity::Error) -> Self {
-        TaskPdfWriterBotError::SerenityError(err)
+        TaskPdfWriterBotError::SerenityError(Box::new(err))
     }
 }
 impl From<sqlx::Error> for TaskPdfWriterBotError {
@@ -113,9 +178,14 @@ impl From<serde_json::Error> for TaskPdfWriterBotError {
         TaskPdfWriterBotError::JsonError(err)
     }
 }
-impl From<openssh::Error> for TaskPdfWriterBotError {
-    fn from(err: openssh::Error) -> Self {
-        TaskPdfWriterBotError::OpenSSHError(err)
+impl From<lopdf::Error> for TaskPdfWriterBotError {
+    fn from(err: lopdf::Error) -> Self {
+        TaskPdfWriterBotError::PdfError(err)
+    }
+}
+impl From<ssh_key::Error> for TaskPdfWriterBotError {
+    fn from(err: ssh_key::Error) -> Self {
+        TaskPdfWriterBotError::SshKeyError(err)
     }
 }
 impl fmt::Display for TaskPdfWriterBotError {
@@ -128,7 +198,9 @@ impl fmt::Display for TaskPdfWriterBotError {
             TaskPdfWriterBotError::IOError(s) => s.fmt(f),
             TaskPdfWriterBotError::ReqwestError(s) => s.fmt(f),
             TaskPdfWriterBotError::JsonError(s) => s.fmt(f),
-            TaskPdfWriterBotError::OpenSSHError(s) => s.fmt(f)
+            TaskPdfWriterBotError::PdfError(s) => s.fmt(f),
+            TaskPdfWriterBotError::SshKeyError(s) => s.fmt(f),
+            TaskPdfWriterBotError::MissingDirectory(s) => s.fmt(f),
         }
     }
 }
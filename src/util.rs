@@ -1,24 +1,28 @@
-use std::os::unix::prelude::PermissionsExt;
-use std::path::Path;
-use std::{env, fs};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
-use git2::Repository;
-use openssh::{KnownHosts, SessionBuilder};
-use serenity::model::prelude::{Channel, ChannelId, GuildId};
+use serenity::model::prelude::{Channel, ChannelId};
 use serenity::prelude::Context;
-use uuid::Uuid;
 
+use crate::contests::ContestRef;
+use crate::credentials::{credentials_callback, Credentials};
+use crate::known_hosts::HostKeyCheck;
+use crate::store::ContestStore;
 use crate::traits::{MyError, TaskPdfWriterBotError};
-use sqlx::FromRow;
-
-use tracing::{debug, info};
-
-#[derive(FromRow)]
-struct Contest {
-    pub guild_id: String,
+pub struct Contest {
     pub git_remote_url: String,
     pub contest_rel_path: String,
     pub private_key: Option<Vec<u8>>,
+    /// The master key version `private_key` is encrypted with.
+    pub private_key_version: Option<i32>,
+    pub https_username: Option<String>,
+    pub https_token: Option<Vec<u8>>,
+    /// The master key version `https_token` is encrypted with.
+    pub https_token_version: Option<i32>,
+    pub renderer_url: Option<String>,
+    pub default_branch: Option<String>,
+    pub default_lang: Option<String>,
 }
 
 pub async fn get_name(
@@ -41,106 +45,203 @@ pub async fn get_name(
 }
 
 pub async fn get_metadata(
-    guild_id: GuildId,
-    database: &sqlx::PgPool,
-) -> Result<(String, String, Option<Vec<u8>>), TaskPdfWriterBotError> {
-    let guild_id_string = guild_id.to_string();
-    let metadata: Result<Contest, sqlx::Error> = sqlx::query_as(
-        r#"SELECT COALESCE(guild_id, 'ID not found') AS "guild_id", COALESCE(git_remote_url, 'URL not found') AS "git_remote_url", COALESCE(contest_rel_path, 'relpath not found') AS "contest_rel_path", private_key FROM contests WHERE guild_id = $1"#).bind(guild_id_string)
-    .fetch_one(database)
-    .await;
-    return match metadata {
-        Err(st) => Err(MyError::new(
-            ("(probably your fault if you haven't config the bot) ".to_string()
-                + st.to_string().as_str())
-            .as_str(),
-        ))?,
-        Ok(res) => Ok((res.git_remote_url, res.contest_rel_path, res.private_key)),
-    };
+    contest: &ContestRef,
+    store: &dyn ContestStore,
+) -> Result<Contest, TaskPdfWriterBotError> {
+    let version = store.contest(contest).await?.unwrap_or_default();
+    match (version.git_remote_url, version.contest_rel_path) {
+        (Some(git_remote_url), Some(contest_rel_path)) => Ok(Contest {
+            git_remote_url,
+            contest_rel_path,
+            private_key: version.private_key,
+            private_key_version: version.private_key_version,
+            https_username: version.https_username,
+            https_token: version.https_token,
+            https_token_version: version.https_token_version,
+            renderer_url: version.renderer_url,
+            default_branch: version.default_branch,
+            default_lang: version.default_lang,
+        }),
+        _ => Err(MyError::new(&format!(
+            "(probably your fault if you haven't config the bot) the contest {} is not configured",
+            contest.name
+        )))?,
+    }
 }
 
-pub async fn prep_repo(
-    guild_id: GuildId,
-    url: String,
-    key: Option<Vec<u8>>,
-) -> Result<Repository, TaskPdfWriterBotError> {
-    let repo_path = env::temp_dir().join(guild_id.to_string()).to_path_buf();
-    debug!("repo_path {:#?}", repo_path.to_str());
-    if repo_path.try_exists()? {
-        fs::remove_dir_all(&repo_path)?;
+/// Turns a relative path from user input (a `reldir`, a thread name, a path in
+/// `config.json`) into one without `.` or `..`, refusing absolute paths and
+/// paths that climb above the directory they are relative to.
+pub fn normalize_relative(path: &Path) -> Result<PathBuf, TaskPdfWriterBotError> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => normalized.push(c),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    Err(MyError::new(&format!(
+                        "(probably your fault): {} leads outside the repository",
+                        path.display()
+                    )))?;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => Err(MyError::new(&format!(
+                "(probably your fault): {} must be a relative path",
+                path.display()
+            )))?,
+        }
     }
-    let pb = env::temp_dir().join(guild_id.to_string() + Uuid::new_v4().to_string().as_str());
-    let privkey_path: &Path = pb.as_path();
+    Ok(normalized)
+}
 
-    // let whoami = session.command("whoami").output().await?;
-    // assert_eq!(whoami.stdout, b"me\n");
+/// Joins `relative` onto `root` and resolves every symlink on the way,
+/// refusing anything that ends up outside `root`. The path itself doesn't
+/// have to exist, but a broken symlink on it is refused.
+pub fn safe_join(
+    root: &Path,
+    relative: impl AsRef<Path>,
+) -> Result<PathBuf, TaskPdfWriterBotError> {
+    let relative = relative.as_ref();
+    let root = root.canonicalize()?;
+    let mut resolved = root.clone();
+    for component in normalize_relative(relative)?.components() {
+        resolved.push(component);
+        if fs::symlink_metadata(&resolved).is_err() {
+            // Nothing on disk below this point, so no symlinks either.
+            continue;
+        }
+        resolved = match resolved.canonicalize() {
+            Ok(p) => p,
+            Err(_) => Err(MyError::new(&format!(
+                "(probably your fault): {} goes through a broken symlink",
+                relative.display()
+            )))?,
+        };
+        if !resolved.starts_with(&root) {
+            Err(MyError::new(&format!(
+                "(probably your fault): {} leads outside the repository",
+                relative.display()
+            )))?;
+        }
+    }
+    Ok(resolved)
+}
+
+/// Builds fetch options that authenticate with `credentials` and verify SSH
+/// host keys with `host_check`.
+pub fn fetch_options<'a>(
+    credentials: &'a Credentials,
+    host_check: &Arc<HostKeyCheck>,
+) -> git2::FetchOptions<'a> {
+    let mut fo = git2::FetchOptions::new();
+    // A redirect could lead to a host the remote policy would refuse.
+    fo.follow_redirects(git2::RemoteRedirect::None);
+    let mut cb = git2::RemoteCallbacks::new();
+    let host_check = host_check.clone();
+    cb.certificate_check(move |cert, _host| host_check.verify(cert));
+    cb.credentials(credentials_callback(credentials));
+    fo.remote_callbacks(cb);
+    fo
+}
 
-    if let Some(k) = key.clone() {
-        if let Ok(()) = fs::write(&privkey_path, &k) {
-            info!("Written privkey");
-        } else {
-            fs::remove_file(&privkey_path)?;
-            Err(MyError::new("cannot write to privkey_path"))?
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use uuid::Uuid;
+
+    /// A scratch directory with a `repo` to sandbox into and a file outside it.
+    struct Scratch(PathBuf);
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = env::temp_dir().join(format!("safe-join-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(dir.join("repo/contest")).unwrap();
+            fs::write(dir.join("repo/contest/task.md"), "# task").unwrap();
+            fs::write(dir.join("secret"), "secret").unwrap();
+            Scratch(dir.canonicalize().unwrap())
+        }
+        fn repo(&self) -> PathBuf {
+            self.0.join("repo")
         }
-        fs::set_permissions(&privkey_path, fs::Permissions::from_mode(0o600))?;
-        if (fs::metadata(&privkey_path)?.permissions().mode() & 0o777) != 0o600 {
-            Err(MyError::new(&format!("permissions not properly set, retreived {:o}, expected {:o}", fs::metadata(&privkey_path)?.permissions().mode() & 0o777, 0o600)))?
+    }
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
         }
+    }
 
-        let session = SessionBuilder::default()
-            .keyfile(&privkey_path)
-            .known_hosts_check(KnownHosts::Accept)
-            .connect("git@github.com")
-            .await?;
-        // let session = Session::connect("git@github.com", KnownHosts::Accept).await?;
+    #[test]
+    fn normalize_relative_removes_dots() {
+        assert_eq!(
+            normalize_relative(Path::new("./a/b/../c")).unwrap(),
+            PathBuf::from("a/c")
+        );
+        assert_eq!(normalize_relative(Path::new(".")).unwrap(), PathBuf::new());
+    }
 
-        let ls = session.command("ls").output().await?;
-        info!(
-            "[Output from SSH] {}",
-            match String::from_utf8(ls.stdout) {
-                Ok(s) => s,
-                Err(e) => format!("{:?}", e)
-            }
+    #[test]
+    fn normalize_relative_refuses_escapes() {
+        assert!(normalize_relative(Path::new("..")).is_err());
+        assert!(normalize_relative(Path::new("a/../../b")).is_err());
+        assert!(normalize_relative(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn safe_join_stays_inside() {
+        let scratch = Scratch::new();
+        let repo = scratch.repo();
+        assert_eq!(
+            safe_join(&repo, "contest/task.md").unwrap(),
+            repo.join("contest/task.md")
+        );
+        assert_eq!(
+            safe_join(&repo, "contest/../contest/task.md").unwrap(),
+            repo.join("contest/task.md")
+        );
+        // Missing files are fine, they are just not found later.
+        assert_eq!(
+            safe_join(&repo, "contest/missing/task.md").unwrap(),
+            repo.join("contest/missing/task.md")
         );
-        session.close().await?;
+    }
 
-        // https://github.com/rust-lang/git2-rs/issues/394
+    #[test]
+    fn safe_join_refuses_traversal() {
+        let scratch = Scratch::new();
+        let repo = scratch.repo();
+        assert!(safe_join(&repo, "../secret").is_err());
+        assert!(safe_join(&repo, "contest/../../secret").is_err());
+        assert!(safe_join(&repo, "../../etc/passwd").is_err());
+        assert!(safe_join(&repo, "/etc/passwd").is_err());
+    }
 
-        //---------------------------------
-        // build up auth credentials via fetch options:
-        let mut cb = git2::RemoteCallbacks::new();
-        cb.credentials(|_, username_from_url, _cred| {
-            // trying https://github.com/rust-lang/git2-rs/issues/329
-            info!("_cred {:?}", _cred);
-            debug!(
-                "[DEBUG privkey] {}",
-                std::str::from_utf8(fs::read(&privkey_path).unwrap().as_slice()).unwrap()
-            );
-            let user = username_from_url.unwrap_or("git");
-            if _cred.contains(git2::CredentialType::USERNAME) {
-                return git2::Cred::username(user);
-            }
-            let credentials = git2::Cred::ssh_key(user, None, privkey_path, None)?;
-            Ok(credentials)
-        });
-        let mut fo = git2::FetchOptions::new();
-        fo.remote_callbacks(cb);
-        //---------------------------
-        // Build builder
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fo);
-        //-------------------
-        // clone
-        let repo = builder.clone(url.as_str(), &repo_path)?;
-        fs::remove_file(&privkey_path)?;
-        Ok(repo)
-    } else {
-        return match Repository::clone(url.as_str(), repo_path) {
-                    Ok(r) => Ok(r),
-                    Err(e) => Err(MyError::new(
-                        ("(probably your fault if the repo is private and you haven't set the private key) ".to_string()
-                            + e.to_string().as_str())
-                        .as_str()))?
-                };
+    #[test]
+    fn safe_join_follows_symlinks_inside() {
+        let scratch = Scratch::new();
+        let repo = scratch.repo();
+        symlink("task.md", repo.join("contest/alias.md")).unwrap();
+        symlink("contest", repo.join("link")).unwrap();
+        assert_eq!(
+            safe_join(&repo, "contest/alias.md").unwrap(),
+            repo.join("contest/task.md")
+        );
+        assert_eq!(
+            safe_join(&repo, "link/task.md").unwrap(),
+            repo.join("contest/task.md")
+        );
+    }
+
+    #[test]
+    fn safe_join_refuses_symlinks_outside() {
+        let scratch = Scratch::new();
+        let repo = scratch.repo();
+        symlink("../../secret", repo.join("contest/leak.md")).unwrap();
+        symlink("/etc", repo.join("etc")).unwrap();
+        symlink("missing", repo.join("contest/broken.md")).unwrap();
+        assert!(safe_join(&repo, "contest/leak.md").is_err());
+        assert!(safe_join(&repo, "etc/passwd").is_err());
+        assert!(safe_join(&repo, "contest/broken.md").is_err());
     }
 }
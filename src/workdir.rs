@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::traits::TaskPdfWriterBotError;
+
+fn jobs_root() -> PathBuf {
+    env::temp_dir().join("task-pdf-writer-v2-bot-jobs")
+}
+
+/// A directory private to one job, removed when dropped, including when the
+/// job fails or panics.
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    pub fn new() -> Result<Self, TaskPdfWriterBotError> {
+        let path = jobs_root().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&path)?;
+        Ok(WorkDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes directories left behind by jobs of a previous run that was
+    /// killed before it could clean up.
+    pub fn remove_stale() -> Result<(), TaskPdfWriterBotError> {
+        let root = jobs_root();
+        if root.try_exists()? {
+            fs::remove_dir_all(root)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}